@@ -1,7 +1,42 @@
+use diagram_base::{OutputFormat, TransformOptions};
+use js_sys::{Array, Object};
+use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 
+/// Reads the files that can be included, given as an object mapping the paths to the sources
+fn included_files(files: &Object) -> Result<HashMap<String, String>, JsValue> {
+    Object::entries(files)
+        .iter()
+        .map(|entry| {
+            let entry = Array::from(&entry);
+            match (entry.get(0).as_string(), entry.get(1).as_string()) {
+                (Some(path), Some(source)) => Ok((path, source)),
+                _ => Err(JsValue::from_str(
+                    "the source of an included file must be a string",
+                )),
+            }
+        })
+        .collect()
+}
+
 #[wasm_bindgen]
-pub fn render(input: String) -> Result<String, JsValue> {
+pub fn render(
+    input: String,
+    format: Option<String>,
+    files: Option<Object>,
+) -> Result<String, JsValue> {
     let input = format!("{}\n", input);
-    diagram_seq::transform(input.as_str()).map_err(|e| e.to_string().into())
+    let options = TransformOptions {
+        format: match format {
+            Some(format) => format.parse::<OutputFormat>()?,
+            None => OutputFormat::default(),
+        },
+        ..TransformOptions::default()
+    };
+    let files = match files {
+        Some(files) => included_files(&files)?,
+        None => HashMap::new(),
+    };
+    diagram_seq::transform_with_resolver(input.as_str(), &options, &files)
+        .map_err(|e| e.diagnostic().render(input.as_str(), None).into())
 }
@@ -1,6 +1,39 @@
-use std::iter::FromIterator;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-type CanvasBuffer = Vec<Vec<char>>;
+/// A single column of the canvas
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cell {
+    /// A grapheme cluster starting in this column
+    Glyph(String),
+    /// The second column of a wide grapheme cluster starting in the previous column
+    Continuation,
+}
+
+type CanvasBuffer = Vec<Vec<Cell>>;
+
+/// The number of columns a grapheme cluster occupies in a terminal
+fn grapheme_width(grapheme: &str) -> usize {
+    // Clusters such as emoji sequences are rendered as a single wide glyph. Zero-width clusters
+    // still take a column, as they would otherwise be impossible to position.
+    grapheme.width().clamp(1, 2)
+}
+
+/// The number of columns the text occupies in a terminal
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Replaces the halves of the wide glyphs overlapping the column with spaces, so that the column
+/// can be safely overwritten
+fn split_wide_glyphs(row: &mut [Cell], column: usize) {
+    if row[column] == Cell::Continuation && column > 0 {
+        row[column - 1] = Cell::Glyph(" ".to_string());
+    }
+    if row.get(column + 1) == Some(&Cell::Continuation) {
+        row[column + 1] = Cell::Glyph(" ".to_string());
+    }
+}
 
 #[derive(Debug)]
 pub struct Rect {
@@ -54,7 +87,7 @@ pub trait Draw {
     fn draw(&mut self, left: usize, top: usize, rows: &[&str]) -> DrawResult {
         // TODO: ensure that the draw cannot happen outside of the region
         let bounds = self.bounds();
-        let max_length = rows.iter().map(|s| s.chars().count()).max().unwrap();
+        let max_length = rows.iter().map(|s| display_width(s)).max().unwrap();
         if max_length > bounds.width - left {
             Err(DrawError::HorizontalOverflow)
         } else if rows.len() > bounds.height - top {
@@ -65,7 +98,18 @@ pub trait Draw {
             let buffer = self.buffer_mut();
             let pairs = buffer[top..top + rows.len()].iter_mut().zip(rows);
             for (brow, drow) in pairs {
-                brow.splice(left..left + drow.chars().count(), drow.chars());
+                let mut column = left;
+                for grapheme in drow.graphemes(true) {
+                    let width = grapheme_width(grapheme);
+                    for offset in 0..width {
+                        split_wide_glyphs(brow, column + offset);
+                    }
+                    brow[column] = Cell::Glyph(grapheme.to_string());
+                    if width == 2 {
+                        brow[column + 1] = Cell::Continuation;
+                    }
+                    column += width;
+                }
             }
             Ok(())
         }
@@ -88,7 +132,7 @@ impl<'a> Draw for TextCanvasRegion<'a> {
     }
 
     fn buffer_mut(&mut self) -> &mut CanvasBuffer {
-        &mut self.buffer
+        self.buffer
     }
 }
 
@@ -101,7 +145,9 @@ impl TextCanvas {
                 width,
                 height,
             },
-            buffer: (0..height).map(|_| vec![' '; width]).collect(),
+            buffer: (0..height)
+                .map(|_| vec![Cell::Glyph(" ".to_string()); width])
+                .collect(),
         }
     }
 
@@ -111,7 +157,7 @@ impl TextCanvas {
         top: usize,
         width: usize,
         height: usize,
-    ) -> TextCanvasRegion {
+    ) -> TextCanvasRegion<'_> {
         let bounds = Rect {
             left,
             top,
@@ -133,7 +179,14 @@ impl TextCanvas {
     pub fn content(&self) -> String {
         self.buffer
             .iter()
-            .map(String::from_iter)
+            .map(|row| {
+                row.iter()
+                    .filter_map(|cell| match cell {
+                        Cell::Glyph(grapheme) => Some(grapheme.as_str()),
+                        Cell::Continuation => None,
+                    })
+                    .collect::<String>()
+            })
             .collect::<Vec<_>>()
             .join("\n")
     }
@@ -150,7 +203,7 @@ mod tests {
         canvas.draw(0, 0, &data).expect("Draw failed");
         canvas.draw(2, 2, &data).expect("Draw failed");
         #[rustfmt::skip]
-        let expected = vec![
+        let expected = [
             "123  ",
             "456  ",
             "78123",
@@ -178,7 +231,8 @@ mod tests {
     fn unicode_boundaries() {
         // Draw a 4 byte character on the canvas, then try to replace it with a 1 byte character
         // and ensure that the replacement process respects the character boundaries.
-        let mut canvas = TextCanvas::new(1, 1);
+        // The character is wide, so the canvas needs to be 2 columns wide to fit it.
+        let mut canvas = TextCanvas::new(2, 1);
 
         let data = ["𩸽"];
         canvas.draw(0, 0, &data).expect("Draw failed");
@@ -186,7 +240,36 @@ mod tests {
 
         let data = ["a"];
         canvas.draw(0, 0, &data).expect("Draw failed");
-        assert_eq!(canvas.content(), data.join("\n"));
+        assert_eq!(canvas.content(), "a ");
+    }
+
+    #[test]
+    fn wide_characters() {
+        let mut canvas = TextCanvas::new(6, 1);
+        canvas.draw(0, 0, &["𩸽😀|"]).expect("Draw failed");
+        assert_eq!(canvas.content(), "𩸽😀| ");
+        assert_eq!(
+            canvas.draw(0, 0, &["𩸽𩸽𩸽a"]),
+            Err(DrawError::HorizontalOverflow)
+        );
+    }
+
+    #[test]
+    fn overwrite_wide_characters() {
+        // Overwriting either half of a wide character clears the other half
+        let mut canvas = TextCanvas::new(4, 1);
+        canvas.draw(0, 0, &["𩸽𩸽"]).expect("Draw failed");
+        canvas.draw(1, 0, &["a"]).expect("Draw failed");
+        assert_eq!(canvas.content(), " a𩸽");
+        canvas.draw(2, 0, &["b"]).expect("Draw failed");
+        assert_eq!(canvas.content(), " ab ");
+    }
+
+    #[test]
+    fn combining_characters() {
+        let mut canvas = TextCanvas::new(2, 1);
+        canvas.draw(0, 0, &["e\u{301}x"]).expect("Draw failed");
+        assert_eq!(canvas.content(), "e\u{301}x");
     }
 
     #[test]
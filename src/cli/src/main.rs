@@ -1,8 +1,12 @@
+#[macro_use]
 extern crate clap;
 
-use clap::{App, Arg};
-use diagram_base::TransformError;
-use std::fs::File;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use diagram_base::{
+    Charset, Diagnostic, FileResolver, OutputFormat, TransformError, TransformOptions,
+};
+use serde::Serialize;
+use std::fs::{self, File};
 use std::io::{self, Read};
 use std::path::Path;
 use std::process;
@@ -19,19 +23,130 @@ fn read_from_stdin() -> io::Result<String> {
     Ok(buffer)
 }
 
-fn read_from_path(path: &str) -> io::Result<String> {
-    let path = Path::new(path);
-    let mut handle = File::open(&path)?;
+fn read_from_path<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut handle = File::open(path)?;
     let mut buffer = String::new();
     handle.read_to_string(&mut buffer)?;
     Ok(buffer)
 }
 
-fn render(input: &str) -> Result<String, TransformError> {
-    let output = diagram_seq::transform(input)?;
+/// Resolves the includes relative to the file including them, or to the working directory for STDIN
+fn file_resolver(path: &str) -> FileResolver {
+    match path {
+        "-" => FileResolver::in_directory(""),
+        path => FileResolver::new(path),
+    }
+}
+
+/// Reads the input from the given path or from STDIN, along with the name to report it under
+fn read_input(path: &str) -> (&str, io::Result<String>) {
+    match path {
+        "-" => ("STDIN", read_from_stdin()),
+        path => (path, read_from_path(path)),
+    }
+}
+
+fn render(
+    input: &str,
+    options: &TransformOptions,
+    resolver: &FileResolver,
+) -> Result<String, TransformError> {
+    let output = diagram_seq::transform_with_resolver(input, options, resolver)?;
     Ok(output)
 }
 
+/// Formats the files in place, or only reports the ones that are not formatted when checking.
+/// Returns whether all the files were formatted successfully.
+fn format_files(matches: &ArgMatches) -> bool {
+    let check = matches.is_present("check");
+    let paths = matches
+        .values_of("PATH")
+        .map_or(vec!["-"], |paths| paths.collect());
+    let mut success = true;
+    for path in paths {
+        let (verbose_path, result) = read_input(path);
+        let input = match result {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("{}: {}", verbose_path, err);
+                success = false;
+                continue;
+            }
+        };
+
+        let output = match diagram_seq::format(&input) {
+            Ok(output) => output,
+            Err(err) => {
+                eprint!("{}", err.diagnostic().render(&input, Some(verbose_path)));
+                success = false;
+                continue;
+            }
+        };
+        if check {
+            if output != input {
+                eprintln!("{}: not formatted", verbose_path);
+                success = false;
+            }
+        } else if path == "-" {
+            print!("{}", output);
+        } else if output != input {
+            if let Err(err) = fs::write(path, output) {
+                eprintln!("{}: {}", verbose_path, err);
+                success = false;
+            }
+        }
+    }
+    success
+}
+
+/// A problem found in a linted file, as printed in the JSON output
+#[derive(Serialize)]
+struct Report<'a> {
+    path: &'a str,
+    #[serde(flatten)]
+    diagnostic: &'a Diagnostic,
+}
+
+/// Reports the problems found in the files. Returns whether all the files could be linted.
+fn lint_files(matches: &ArgMatches) -> bool {
+    let json = matches.value_of("format") == Some("json");
+    let paths = matches
+        .values_of("PATH")
+        .map_or(vec!["-"], |paths| paths.collect());
+    let mut success = true;
+    for path in paths {
+        let (verbose_path, result) = read_input(path);
+        let input = match result {
+            Ok(input) => input,
+            Err(err) => {
+                eprintln!("{}: {}", verbose_path, err);
+                success = false;
+                continue;
+            }
+        };
+
+        let diagnostics = match diagram_seq::lint(&input, &file_resolver(path)) {
+            Ok(diagnostics) => diagnostics,
+            Err(err) => {
+                success = false;
+                vec![err.diagnostic().clone()]
+            }
+        };
+        for diagnostic in &diagnostics {
+            if json {
+                let report = Report {
+                    path: verbose_path,
+                    diagnostic,
+                };
+                println!("{}", serde_json::to_string(&report).unwrap());
+            } else {
+                eprint!("{}", diagnostic.render(&input, Some(verbose_path)));
+            }
+        }
+    }
+    success
+}
+
 fn main() -> io::Result<()> {
     let matches = App::new(PKG_NAME)
         .version(PKG_VERSION)
@@ -43,26 +158,115 @@ fn main() -> io::Result<()> {
                 .required(false)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("The format of the generated diagram.")
+                .possible_values(&["ascii", "svg", "json-ast", "json-layout"])
+                .default_value("ascii"),
+        )
+        .arg(
+            Arg::with_name("charset")
+                .long("charset")
+                .value_name("CHARSET")
+                .help("The characters text diagrams are drawn with.")
+                .possible_values(&["unicode", "ascii", "rounded"])
+                .default_value("unicode"),
+        )
+        .arg(
+            Arg::with_name("max-label-width")
+                .long("max-label-width")
+                .value_name("COLUMNS")
+                .help(
+                    "Word-wrap the message labels that are wider than the given number of columns.",
+                )
+                .validator(|value| match value.parse::<usize>() {
+                    Ok(width) if width > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                }),
+        )
+        .arg(
+            Arg::with_name("hide-footbox")
+                .long("hide-footbox")
+                .help("Do not repeat the participants below the lifelines."),
+        )
+        .arg(
+            Arg::with_name("reorder-participants")
+                .long("reorder-participants")
+                .help("Order the undeclared participants so that the messages are as short as possible."),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Formats .diag files in place")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Paths to the .diag files to format (- for STDIN).")
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Do not write the files, fail if any of them is not formatted."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lint")
+                .about("Reports the problems in .diag files that do not prevent them from being drawn")
+                .arg(
+                    Arg::with_name("PATH")
+                        .help("Paths to the .diag files to lint (- for STDIN).")
+                        .multiple(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("The format of the reported problems, one JSON object per line for json.")
+                        .possible_values(&["human", "json"])
+                        .default_value("human"),
+                ),
+        )
         .get_matches();
 
-    let input = {
-        let path = matches.value_of("PATH").unwrap_or("-");
-        let (verbose_path, result) = match path {
-            "-" => ("STDIN", read_from_stdin()),
-            path => (path, read_from_path(path)),
-        };
-        result.unwrap_or_else(|e| {
-            eprintln!("{}: {}", verbose_path, e);
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        if !format_files(matches) {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("lint") {
+        if !lint_files(matches) {
             process::exit(1);
-        })
+        }
+        return Ok(());
+    }
+
+    let options = TransformOptions {
+        format: value_t!(matches, "format", OutputFormat).unwrap_or_else(|e| e.exit()),
+        charset: value_t!(matches, "charset", Charset).unwrap_or_else(|e| e.exit()),
+        max_label_width: matches
+            .value_of("max-label-width")
+            .map(|width| width.parse().unwrap()),
+        hide_footbox: matches.is_present("hide-footbox"),
+        reorder_participants: matches.is_present("reorder-participants"),
     };
 
-    let output = render(input.as_str());
+    let path = matches.value_of("PATH").unwrap_or("-");
+    let (verbose_path, result) = read_input(path);
+    let input = result.unwrap_or_else(|e| {
+        eprintln!("{}: {}", verbose_path, e);
+        process::exit(1);
+    });
+
+    let output = render(input.as_str(), &options, &file_resolver(path));
     match output {
         Ok(repr) if !repr.is_empty() => println!("{}", repr),
         Ok(_) => eprintln!("Warning: No diagram was generated"),
         Err(err) => {
-            eprintln!("{}", err);
+            eprint!("{}", err.diagnostic().render(&input, Some(verbose_path)));
             process::exit(1);
         }
     }
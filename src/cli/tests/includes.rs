@@ -0,0 +1,28 @@
+use std::process::{Command, Output};
+
+/// Runs the CLI on the given fixture
+fn diagwiz(fixture: &str) -> Output {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), fixture);
+    Command::new(env!("CARGO_BIN_EXE_diagwiz"))
+        .arg(path)
+        .output()
+        .expect("Failed to run diagwiz")
+}
+
+#[test]
+fn test_include_cycle() {
+    let output = diagwiz("cycle/a.diag");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("error[E0006]: b.diag:1:1: `a.diag` includes itself"));
+    assert!(stderr.contains("a.diag:1:1"));
+}
+
+#[test]
+fn test_self_include() {
+    let output = diagwiz("cycle/self.diag");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("error[E0006]: `self.diag` includes itself"));
+    assert!(stderr.contains("self.diag:2:1"));
+}
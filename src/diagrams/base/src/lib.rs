@@ -1,14 +1,425 @@
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use unicode_width::UnicodeWidthChar;
+
+/// How serious a reported problem is
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The diagram cannot be generated
+    Error,
+    /// The diagram can be generated, but probably not the way it was intended
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A location in the source, both the line and the column are 1-based
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A range of the source, the end is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
+    }
+}
+
+/// The number of columns between two tab stops of the printed source
+const TAB_WIDTH: usize = 4;
+
+/// Expands the tabs of the line the same way a terminal would, along with the display column each
+/// character starts at (and the one right after the last character)
+fn expand_line(line: &str) -> (String, Vec<usize>) {
+    let mut output = String::with_capacity(line.len());
+    let mut columns = Vec::with_capacity(line.len() + 1);
+    let mut column = 0;
+    for c in line.chars() {
+        columns.push(column);
+        match c {
+            '\t' => {
+                let width = TAB_WIDTH - column % TAB_WIDTH;
+                output.push_str(&" ".repeat(width));
+                column += width;
+            }
+            _ => {
+                output.push(c);
+                column += c.width().unwrap_or(0);
+            }
+        }
+    }
+    columns.push(column);
+    (output, columns)
+}
+
+/// A problem found in the source of a diagram
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A stable identifier of the kind of the problem, e.g. E0001
+    pub code: String,
+    pub message: String,
+    /// The part of the source the problem was found in, if it can be attributed to one
+    pub span: Option<Span>,
+    /// A hint on how to fix the problem
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &str, message: &str) -> Self {
+        Self {
+            severity,
+            code: code.to_string(),
+            message: message.to_string(),
+            span: None,
+            help: None,
+        }
+    }
+
+    pub fn error(code: &str, message: &str) -> Self {
+        Self::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code: &str, message: &str) -> Self {
+        Self::new(Severity::Warning, code, message)
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    /// Renders the diagnostic together with the annotated line of the source it points at.
+    /// The origin (e.g. a file path) is included in the location line if given.
+    pub fn render(&self, source: &str, origin: Option<&str>) -> String {
+        let mut output = format!("{}[{}]: {}\n", self.severity, self.code, self.message);
+        let origin = origin
+            .map(|origin| format!("{}:", origin))
+            .unwrap_or_default();
+        let span = match self.span {
+            Some(span) => span,
+            None => {
+                if !origin.is_empty() {
+                    output.push_str(&format!(" --> {}\n", origin.trim_end_matches(':')));
+                }
+                if let Some(help) = &self.help {
+                    output.push_str(&format!(" = help: {}\n", help));
+                }
+                return output;
+            }
+        };
+
+        let line = source.lines().nth(span.start.line - 1).unwrap_or("");
+        let line_number = span.start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        // Underline up to the end of the span or the end of the line, but at least one column. The
+        // columns of the span count characters, which are converted to the columns of the terminal.
+        let (line, columns) = expand_line(line);
+        let display_column = |column: usize| match columns.get(column - 1) {
+            Some(&display_column) => display_column,
+            None => columns[columns.len() - 1] + column - columns.len(),
+        };
+        let end_column = match span.end.line == span.start.line {
+            true => span.end.column,
+            false => columns.len(),
+        };
+        let start = display_column(span.start.column);
+        let end = display_column(end_column.max(span.start.column));
+        let carets = "^".repeat((end - start).max(1));
+        let indent = " ".repeat(start);
+
+        output.push_str(&format!("{} --> {}{}\n", gutter, origin, span.start));
+        output.push_str(&format!("{} |\n", gutter));
+        output.push_str(&format!("{} | {}\n", line_number, line));
+        output.push_str(&format!("{} | {}{}\n", gutter, indent, carets));
+        if let Some(help) = &self.help {
+            output.push_str(&format!("{} = help: {}\n", gutter, help));
+        }
+        output
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]: {}", self.severity, self.code, self.message)?;
+        if let Some(span) = self.span {
+            write!(f, " at {}", span.start)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum TransformError {
-    ParseError(String),
+    /// The source of the diagram is invalid
+    ParseError(Box<Diagnostic>),
+    /// The diagram could not be drawn
+    RenderError(Box<Diagnostic>),
+}
+
+impl TransformError {
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            TransformError::ParseError(diagnostic) => diagnostic,
+            TransformError::RenderError(diagnostic) => diagnostic,
+        }
+    }
 }
 
 impl fmt::Display for TransformError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            TransformError::ParseError(msg) => write!(f, "Invalid syntax:\n{}", msg),
+        write!(f, "{}", self.diagnostic())
+    }
+}
+
+/// The representation a diagram is generated in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Text drawn with box-drawing characters
+    #[default]
+    Ascii,
+    /// Scalable vector graphics
+    Svg,
+    /// The parsed diagram as JSON
+    JsonAst,
+    /// The positions and sizes of the laid out elements as JSON
+    JsonLayout,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascii" => Ok(OutputFormat::Ascii),
+            "svg" => Ok(OutputFormat::Svg),
+            "json-ast" => Ok(OutputFormat::JsonAst),
+            "json-layout" => Ok(OutputFormat::JsonLayout),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+/// The set of characters text diagrams are drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Charset {
+    /// Box-drawing characters
+    #[default]
+    Unicode,
+    /// Plain ASCII, for the places that mangle anything else
+    Ascii,
+    /// Box-drawing characters with rounded corners
+    Rounded,
+}
+
+impl FromStr for Charset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unicode" => Ok(Charset::Unicode),
+            "ascii" => Ok(Charset::Ascii),
+            "rounded" => Ok(Charset::Rounded),
+            _ => Err(format!("unknown charset `{}`", s)),
+        }
+    }
+}
+
+/// A file included by a diagram
+#[derive(Debug, Clone, PartialEq)]
+pub struct Include {
+    /// The name identifying the file, e.g. its canonical path. Including a file with the same name
+    /// as one of the files including it is reported as a cycle.
+    pub name: String,
+    pub source: String,
+}
+
+/// Provides the sources of the files included by the diagrams, e.g. from the filesystem or from
+/// memory
+pub trait IncludeResolver {
+    /// Finds the file included with the given path by the file with the given name, or by the
+    /// input of the diagram itself if there is no name
+    fn resolve(&self, path: &str, includer: Option<&str>) -> io::Result<Include>;
+
+    /// The name of the input of the diagram itself in case it is a file, so that including it
+    /// back is reported as a cycle. Its includes are resolved with this name as the includer.
+    fn name(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Rejects all the includes, for the diagrams that are not allowed to include anything
+pub struct NoIncludes;
+
+impl IncludeResolver for NoIncludes {
+    fn resolve(&self, _path: &str, _includer: Option<&str>) -> io::Result<Include> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "includes are not available",
+        ))
+    }
+}
+
+/// Resolves the includes from memory, using the paths as the names of the files
+impl IncludeResolver for HashMap<String, String> {
+    fn resolve(&self, path: &str, _includer: Option<&str>) -> io::Result<Include> {
+        match self.get(path) {
+            Some(source) => Ok(Include {
+                name: path.to_string(),
+                source: source.clone(),
+            }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+}
+
+/// Reads the included files from the disk, relative to the file including them
+pub struct FileResolver {
+    /// The directory the includes of the diagram itself are relative to
+    root: PathBuf,
+    /// The canonical path of the diagram itself, if it is read from a file
+    input: Option<PathBuf>,
+}
+
+impl FileResolver {
+    /// Resolves the includes of the diagram read from the file at the given path
+    pub fn new<P: AsRef<Path>>(input: P) -> Self {
+        let input = input.as_ref();
+        Self {
+            root: input.parent().map(Path::to_path_buf).unwrap_or_default(),
+            input: fs::canonicalize(input).ok(),
         }
     }
+
+    /// Resolves the includes of a diagram that is not read from a file, e.g. from STDIN, relative
+    /// to the given directory
+    pub fn in_directory<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            input: None,
+        }
+    }
+}
+
+impl IncludeResolver for FileResolver {
+    fn resolve(&self, path: &str, includer: Option<&str>) -> io::Result<Include> {
+        let dir = match includer {
+            Some(includer) => Path::new(includer)
+                .parent()
+                .unwrap_or_else(|| Path::new("")),
+            None => &self.root,
+        };
+        let path = fs::canonicalize(dir.join(path))?;
+        Ok(Include {
+            source: fs::read_to_string(&path)?,
+            name: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    fn name(&self) -> Option<String> {
+        self.input
+            .as_ref()
+            .map(|input| input.to_string_lossy().into_owned())
+    }
+}
+
+/// Options controlling how a diagram is generated
+#[derive(Debug, Clone, Default)]
+pub struct TransformOptions {
+    pub format: OutputFormat,
+    /// The characters text diagrams are drawn with, ignored by the other formats
+    pub charset: Charset,
+    /// Word-wrap the labels that are wider than this many columns
+    pub max_label_width: Option<usize>,
+    /// Do not mirror the participants below the lifelines
+    pub hide_footbox: bool,
+    /// Reorder the participants to shorten the messages, unless the diagram declares their order
+    pub reorder_participants: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_diagnostic() {
+        let diagnostic = Diagnostic::error("E0001", "expected an arrow")
+            .with_span(Span::new(Position::new(2, 3), Position::new(2, 6)))
+            .with_help("messages are written as a->b");
+        assert_eq!(
+            diagnostic.render("a->b\nb  c\n", Some("test.diag")),
+            concat!(
+                "error[E0001]: expected an arrow\n",
+                "  --> test.diag:2:3\n",
+                "  |\n",
+                "2 | b  c\n",
+                "  |   ^^^\n",
+                "  = help: messages are written as a->b\n",
+            )
+        );
+    }
+
+    #[test]
+    fn render_diagnostic_after_wide_characters() {
+        let diagnostic = Diagnostic::error("E0001", "expected an arrow")
+            .with_span(Span::new(Position::new(1, 5), Position::new(1, 7)));
+        assert_eq!(
+            diagnostic.render("\t漢字 ab", None),
+            concat!(
+                "error[E0001]: expected an arrow\n",
+                "  --> 1:5\n",
+                "  |\n",
+                "1 |     漢字 ab\n",
+                "  |          ^^\n",
+            )
+        );
+    }
+
+    #[test]
+    fn render_diagnostic_without_span() {
+        let diagnostic = Diagnostic::warning("W0001", "unused alias");
+        assert_eq!(
+            diagnostic.render("", None),
+            "warning[W0001]: unused alias\n"
+        );
+    }
 }
@@ -0,0 +1,173 @@
+use crate::parser::{self, span_of, syntax_error, ParserError, Rule, SequenceDiagramParser};
+use crate::renderer::collect_participants;
+use diagram_base::{IncludeResolver, Span};
+use pest::iterators::Pair;
+use pest::Parser;
+
+/// An occurrence of a participant ID in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+    pub id: String,
+    pub span: Span,
+    /// Whether the occurrence declares an alias of the participant
+    pub declaration: bool,
+}
+
+/// A participant of the diagram along with the label it is drawn with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Participant {
+    pub id: String,
+    pub label: String,
+}
+
+/// What the editors need to know about the participants of a diagram
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    /// The occurrences of the participant IDs in the analysed input, in the order they appear
+    pub occurrences: Vec<Occurrence>,
+    /// The participants in the order they are declared or first used, including the ones declared
+    /// in the included files
+    pub participants: Vec<Participant>,
+}
+
+impl Analysis {
+    /// Finds the occurrence of a participant ID at the given position
+    pub fn occurrence_at(&self, line: usize, column: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|occurrence| {
+            let Span { start, end } = occurrence.span;
+            start.line == line && start.column <= column && column <= end.column
+        })
+    }
+
+    /// Finds the alias declaration in effect for the participant, i.e. the last one
+    pub fn declaration(&self, id: &str) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .rev()
+            .find(|occurrence| occurrence.declaration && occurrence.id == id)
+    }
+
+    pub fn participant(&self, id: &str) -> Option<&Participant> {
+        self.participants
+            .iter()
+            .find(|participant| participant.id == id)
+    }
+}
+
+/// Collects the IDs in the subtree, the ones directly within an alias declare it
+fn collect_occurrences(pair: Pair<Rule>, declaration: bool, occurrences: &mut Vec<Occurrence>) {
+    match pair.as_rule() {
+        Rule::identifier => occurrences.push(Occurrence {
+            id: pair.as_str().to_string(),
+            span: span_of(&pair),
+            declaration,
+        }),
+        rule => pair
+            .into_inner()
+            .for_each(|inner| collect_occurrences(inner, rule == Rule::alias, occurrences)),
+    }
+}
+
+pub fn analyze(input: &str, resolver: &dyn IncludeResolver) -> Result<Analysis, ParserError> {
+    let diag = parser::diagram(input, resolver)?;
+    let mut ids: Vec<&str> = diag.participants.iter().map(|id| &id[..]).collect();
+    collect_participants(&diag.events, &mut ids);
+    let mut participants: Vec<Participant> = Vec::new();
+    for id in ids {
+        if participants.iter().all(|participant| participant.id != id) {
+            // The last declaration wins, the same way as when the diagram is drawn
+            let label = match diag.aliases.iter().rev().find(|alias| alias.id == id) {
+                Some(alias) => alias.label.clone(),
+                None => id.to_string(),
+            };
+            participants.push(Participant {
+                id: id.to_string(),
+                label,
+            });
+        }
+    }
+
+    let mut occurrences = Vec::new();
+    for pair in SequenceDiagramParser::parse(Rule::main, input).map_err(syntax_error)? {
+        collect_occurrences(pair, false, &mut occurrences);
+    }
+    Ok(Analysis {
+        occurrences,
+        participants,
+    })
+}
+
+/// Tells whether the text can be used as a participant ID
+pub fn is_identifier(text: &str) -> bool {
+    match SequenceDiagramParser::parse(Rule::identifier, text) {
+        Ok(mut pairs) => pairs.next().map(|pair| pair.as_str()) == Some(text),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use diagram_base::{NoIncludes, Position};
+    use std::collections::HashMap;
+
+    #[test]
+    fn analyze_participants() {
+        let mut files = HashMap::new();
+        files.insert("common.diag".to_string(), "alias c=\"Carol\"\n".to_string());
+        let data = "include \"common.diag\"\nalias a=\"Alice\"\na->b\nb->c\nalias a=\"Ann\"\n";
+        let analysis = analyze(data, &files).unwrap();
+        assert_eq!(
+            analysis
+                .participants
+                .iter()
+                .map(|participant| (&participant.id[..], &participant.label[..]))
+                .collect::<Vec<_>>(),
+            vec![("c", "Carol"), ("a", "Ann"), ("b", "b")]
+        );
+        assert_eq!(
+            analysis
+                .occurrences
+                .iter()
+                .map(|occurrence| (&occurrence.id[..], occurrence.declaration))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a", true),
+                ("a", false),
+                ("b", false),
+                ("b", false),
+                ("c", false),
+                ("a", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn find_occurrences_and_declarations() {
+        let data = "alias alice=\"Alice\"\nnote over alice, bob: \"hi\"\nalias alice=\"Al\"\n";
+        let analysis = analyze(data, &NoIncludes).unwrap();
+        let occurrence = analysis.occurrence_at(2, 18).unwrap();
+        assert_eq!(occurrence.id, "bob");
+        assert_eq!(
+            occurrence.span,
+            Span::new(Position::new(2, 18), Position::new(2, 21))
+        );
+        assert_eq!(analysis.occurrence_at(2, 22), None);
+        assert_eq!(
+            analysis.declaration("alice").unwrap().span.start,
+            Position::new(3, 7)
+        );
+        assert_eq!(analysis.declaration("bob"), None);
+    }
+
+    #[test]
+    fn validate_identifiers() {
+        assert!(is_identifier("alice_2"));
+        assert!(!is_identifier("2alice"));
+        assert!(!is_identifier("alias"));
+        // Only reserved at the start of a statement
+        assert!(is_identifier("alt"));
+        assert!(!is_identifier("alice bob"));
+        assert!(!is_identifier(""));
+    }
+}
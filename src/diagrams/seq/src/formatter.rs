@@ -0,0 +1,454 @@
+use crate::parser::{syntax_error, ParserError, Rule, SequenceDiagramParser};
+use pest::iterators::Pair;
+use pest::Parser;
+
+const INDENT: &str = "    ";
+
+/// A line of the source. The parser skips the comments and the blank lines, while the formatter
+/// has to keep them, so they are recovered from the text between the statements.
+#[derive(Debug)]
+enum Line<'i> {
+    Blank,
+    Comment(&'i str),
+    Statement(Statement<'i>),
+}
+
+/// A statement with the comment following it on the same line
+#[derive(Debug)]
+struct Statement<'i> {
+    node: Node<'i>,
+    comment: Option<&'i str>,
+}
+
+#[derive(Debug)]
+enum Node<'i> {
+    /// A statement fitting on one line, in its canonical form
+    Simple(String),
+    /// An alias, the labels of the adjacent aliases are aligned
+    Alias { declaration: String, label: String },
+    /// A statement spanning several lines, e.g. a fragment and its sections
+    Nested(Vec<Section<'i>>),
+}
+
+/// A header followed by the lines within the braces
+#[derive(Debug)]
+struct Section<'i> {
+    header: String,
+    /// The comment following the opening brace
+    comment: Option<&'i str>,
+    lines: Vec<Line<'i>>,
+}
+
+/// Finds where the comment starts in a text containing no strings
+fn comment_start(text: &str) -> Option<usize> {
+    match (text.find('#'), text.find("//")) {
+        (Some(hash), Some(slashes)) => Some(hash.min(slashes)),
+        (Some(start), None) | (None, Some(start)) => Some(start),
+        (None, None) => None,
+    }
+}
+
+/// Finds the comment in a line of the text between the statements
+fn comment(line: &str) -> Option<&str> {
+    comment_start(line).map(|start| line[start..].trim_end())
+}
+
+/// Finds where the statement ends. The parser skips the whitespace and the comments before trying
+/// the optional tokens, so the span of a statement ending with one may include a comment.
+fn end_of(pair: &Pair<Rule>) -> usize {
+    let span = pair.as_span();
+    let last_token = pair
+        .clone()
+        .into_inner()
+        .flatten()
+        .map(|token| token.as_span().end())
+        .max()
+        .unwrap_or(span.start());
+    let tail = &pair.as_str()[last_token - span.start()..];
+    let tail = &tail[..comment_start(tail).unwrap_or(tail.len())];
+    last_token + tail.trim_end().len()
+}
+
+/// Splits the text between two statements into the comment ending the line of the first one and
+/// the lines in between
+fn gap(text: &str) -> (Option<&str>, Vec<Line<'_>>) {
+    let mut segments = text.split('\n');
+    let first = segments.next().and_then(comment);
+    let rest: Vec<&str> = segments.collect();
+    let lines = rest
+        .iter()
+        .enumerate()
+        .filter_map(|(i, segment)| match comment(segment) {
+            Some(text) => Some(Line::Comment(text)),
+            // The last segment is the indentation of the next statement
+            None if i + 1 < rest.len() => Some(Line::Blank),
+            None => None,
+        })
+        .collect();
+    (first, lines)
+}
+
+/// Collects the statements between the given offsets, along with the comments and the blank
+/// lines around them. Returns the comment following the opening brace separately.
+fn body<'i>(
+    input: &'i str,
+    start: usize,
+    end: usize,
+    statements: impl Iterator<Item = Pair<'i, Rule>>,
+) -> (Option<&'i str>, Vec<Line<'i>>) {
+    let mut lines = Vec::new();
+    let mut opening_comment = None;
+    let mut previous: Option<usize> = None;
+    let mut position = start;
+    let mut attach = |lines: &mut Vec<Line<'i>>, previous: Option<usize>, text: &'i str| {
+        let (comment, trivia) = gap(text);
+        match previous.map(|i| &mut lines[i]) {
+            Some(Line::Statement(statement)) => statement.comment = comment,
+            _ => opening_comment = comment,
+        }
+        lines.extend(trivia);
+    };
+    for pair in statements {
+        let span = pair.as_span();
+        attach(&mut lines, previous, &input[position..span.start()]);
+        previous = Some(lines.len());
+        position = end_of(&pair);
+        lines.push(Line::Statement(Statement {
+            node: node(input, pair),
+            comment: None,
+        }));
+    }
+    attach(&mut lines, previous, &input[position..end]);
+    (opening_comment, lines)
+}
+
+/// The canonical form of the tokens of a statement, the strings keep their escape sequences
+fn tokens(pair: Pair<Rule>) -> Vec<String> {
+    pair.into_inner()
+        .filter(|token| token.as_rule() != Rule::string_end)
+        .map(|token| match token.as_rule() {
+            Rule::string_inner => format!("\"{}\"", token.as_str()),
+            Rule::note_left | Rule::note_right | Rule::note_over => {
+                let ids = tokens(token.clone()).join(", ");
+                match token.as_rule() {
+                    Rule::note_left => format!("left of {}", ids),
+                    Rule::note_right => format!("right of {}", ids),
+                    _ => format!("over {}", ids),
+                }
+            }
+            Rule::participant_kind | Rule::fragment_kind | Rule::autonumber_keyword => {
+                token.as_str().to_lowercase()
+            }
+            _ => token.as_str().trim().to_string(),
+        })
+        .collect()
+}
+
+/// Formats a statement written on one line
+fn simple(pair: Pair<Rule>) -> String {
+    let rule = pair.as_rule();
+    let mut tokens = tokens(pair);
+    match rule {
+        Rule::title => format!("title {}", tokens[0]),
+        Rule::header => format!("header {}", tokens[0]),
+        Rule::footer => format!("footer {}", tokens[0]),
+        Rule::include => format!("include {}", tokens[0]),
+        Rule::hide_footbox => String::from("hide footbox"),
+        Rule::participant_list => format!("participant {}", tokens.join(", ")),
+        Rule::activate => format!("activate {}", tokens[0]),
+        Rule::deactivate => format!("deactivate {}", tokens[0]),
+        Rule::create => format!("create {}", tokens[0]),
+        Rule::destroy => format!("destroy {}", tokens[0]),
+        Rule::autonumber => tokens.join(" "),
+        Rule::divider => match tokens.pop() {
+            Some(label) if !label.is_empty() => format!("== {} ==", label),
+            _ => String::from("===="),
+        },
+        Rule::delay => match tokens.pop() {
+            Some(label) => format!("... {} ...", label),
+            None => String::from("..."),
+        },
+        Rule::note | Rule::reference => {
+            let label = tokens.pop().unwrap();
+            match rule {
+                Rule::note => format!("note {}: {}", tokens[0], label),
+                _ => format!("ref over {}: {}", tokens.join(", "), label),
+            }
+        }
+        Rule::pair => {
+            // The label is the only quoted token
+            let label = match tokens.last() {
+                Some(token) if token.starts_with('"') => tokens.pop(),
+                _ => None,
+            };
+            let message = tokens.concat();
+            match label {
+                Some(label) => format!("{}: {}", message, label),
+                None => message,
+            }
+        }
+        _ => unreachable!("{:?} is not a statement", rule),
+    }
+}
+
+fn node<'i>(input: &'i str, pair: Pair<'i, Rule>) -> Node<'i> {
+    match pair.as_rule() {
+        Rule::alias => {
+            let mut tokens = tokens(pair);
+            let label = tokens.pop().unwrap();
+            let id = tokens.pop().unwrap();
+            let keyword = tokens.pop().unwrap_or_else(|| String::from("alias"));
+            Node::Alias {
+                declaration: format!("{} {}", keyword, id),
+                label,
+            }
+        }
+        Rule::fragment => {
+            // { fragment_kind ~ section ~ (NEWLINE* ~ ^"else" ~ section)* }
+            let mut inner = pair.into_inner();
+            let mut keyword = inner.next().unwrap().as_str().to_lowercase();
+            let mut sections: Vec<Section> = Vec::new();
+            let mut position = None;
+            for section in inner {
+                // The comments between the sections go to the end of the preceding one
+                if let (Some(position), Some(last)) = (position, sections.last_mut()) {
+                    let (comment, trivia) = gap(&input[position..section.as_span().start()]);
+                    last.lines.extend(comment.map(Line::Comment));
+                    last.lines.extend(
+                        trivia
+                            .into_iter()
+                            .filter(|line| matches!(line, Line::Comment(_))),
+                    );
+                }
+                position = Some(section.as_span().end());
+
+                // { string? ~ block }
+                let mut header = keyword;
+                let mut block = None;
+                for token in section.into_inner() {
+                    match token.as_rule() {
+                        Rule::string_inner => header = format!("{} \"{}\"", header, token.as_str()),
+                        Rule::block => block = Some(token),
+                        _ => {}
+                    }
+                }
+                let block = block.unwrap();
+                let span = block.as_span();
+                let (comment, lines) =
+                    body(input, span.start() + 1, span.end() - 1, block.into_inner());
+                sections.push(Section {
+                    header,
+                    comment,
+                    lines,
+                });
+                keyword = String::from("else");
+            }
+            Node::Nested(sections)
+        }
+        Rule::participant_box => {
+            // { ^"box " ~ string ~ "{" ~ (box_member? ~ NEWLINE)* ~ box_member? ~ "}" }
+            let end = pair.as_span().end() - 1;
+            let mut inner = pair.into_inner();
+            let label = inner.next().unwrap().as_str();
+            let string_end = inner.next().unwrap().as_span().end();
+            let start = string_end + input[string_end..].find('{').unwrap() + 1;
+            let (comment, lines) = body(input, start, end, inner);
+            Node::Nested(vec![Section {
+                header: format!("box \"{}\"", label),
+                comment,
+                lines,
+            }])
+        }
+        Rule::identifier => Node::Simple(pair.as_str().to_string()),
+        _ => Node::Simple(simple(pair)),
+    }
+}
+
+fn with_comment(line: String, comment: Option<&str>) -> String {
+    match comment {
+        Some(comment) => format!("{} {}", line, comment),
+        None => line,
+    }
+}
+
+/// Prints the lines with the given indentation, keeping at most one blank line between them
+fn print(lines: &[Line], depth: usize, output: &mut Vec<String>) {
+    let indent = INDENT.repeat(depth);
+    let first = lines.iter().position(|line| !matches!(line, Line::Blank));
+    let last = lines.iter().rposition(|line| !matches!(line, Line::Blank));
+    let lines = match (first, last) {
+        (Some(first), Some(last)) => &lines[first..=last],
+        _ => return,
+    };
+
+    // The width of the declarations in the current block of adjacent aliases
+    let mut alias_width = None;
+    for (i, line) in lines.iter().enumerate() {
+        let statement = match line {
+            Line::Blank => {
+                if !matches!(lines[i - 1], Line::Blank) {
+                    output.push(String::new());
+                }
+                alias_width = None;
+                continue;
+            }
+            Line::Comment(comment) => {
+                output.push(format!("{}{}", indent, comment));
+                alias_width = None;
+                continue;
+            }
+            Line::Statement(statement) => statement,
+        };
+        match &statement.node {
+            Node::Simple(text) => {
+                output.push(with_comment(
+                    format!("{}{}", indent, text),
+                    statement.comment,
+                ));
+                alias_width = None;
+            }
+            Node::Alias { declaration, label } => {
+                let width = *alias_width.get_or_insert_with(|| {
+                    lines[i..]
+                        .iter()
+                        .map_while(|line| match line {
+                            Line::Statement(Statement {
+                                node: Node::Alias { declaration, .. },
+                                ..
+                            }) => Some(declaration.len()),
+                            _ => None,
+                        })
+                        .max()
+                        .unwrap()
+                });
+                let text = format!(
+                    "{}{:width$} = {}",
+                    indent,
+                    declaration,
+                    label,
+                    width = width
+                );
+                output.push(with_comment(text, statement.comment));
+            }
+            Node::Nested(sections) => {
+                for (j, section) in sections.iter().enumerate() {
+                    let opening = match j {
+                        0 => format!("{}{} {{", indent, section.header),
+                        _ => format!("{}}} {} {{", indent, section.header),
+                    };
+                    output.push(with_comment(opening, section.comment));
+                    print(&section.lines, depth + 1, output);
+                }
+                output.push(with_comment(format!("{}}}", indent), statement.comment));
+                alias_width = None;
+            }
+        }
+    }
+}
+
+/// Formats the diagram canonically, keeping its comments
+pub fn format(input: &str) -> Result<String, ParserError> {
+    let main = SequenceDiagramParser::parse(Rule::main, input)
+        .map_err(syntax_error)?
+        .next()
+        .unwrap();
+    let statements = main.into_inner().filter(|pair| pair.as_rule() != Rule::EOI);
+    let (comment, mut lines) = body(input, 0, input.len(), statements);
+    // There is no opening brace, so the comment is on the first line
+    if let Some(comment) = comment {
+        lines.insert(0, Line::Comment(comment));
+    }
+
+    let mut output = Vec::new();
+    print(&lines, 0, &mut output);
+    Ok(output
+        .into_iter()
+        .map(|line| line + "\n")
+        .collect::<String>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::diagram;
+    use diagram_base::NoIncludes;
+
+    #[test]
+    fn normalises_spacing_and_keywords() {
+        let data =
+            "TITLE   \"Demo\"\nalias a=\"Alice\"\na  ->+  b : \"hi\"\nnote over a,b: \"n\"\n";
+        assert_eq!(
+            format(data).unwrap(),
+            "title \"Demo\"\nalias a = \"Alice\"\na->+b: \"hi\"\nnote over a, b: \"n\"\n"
+        );
+    }
+
+    #[test]
+    fn aligns_adjacent_aliases() {
+        let data = "alias a=\"Alice\"\nactor bob=\"Bob\"\n\nalias c=\"Carol\"\n";
+        assert_eq!(
+            format(data).unwrap(),
+            "alias a   = \"Alice\"\nactor bob = \"Bob\"\n\nalias c = \"Carol\"\n"
+        );
+    }
+
+    #[test]
+    fn preserves_comments() {
+        let data = r#"# Leading
+a->b # after a message
+alt "ok" { // after a brace
+  # inside
+  b->a
+} # between sections
+else {
+}
+autonumber # after an optional token
+"#;
+        assert_eq!(
+            format(data).unwrap(),
+            r#"# Leading
+a->b # after a message
+alt "ok" { // after a brace
+    # inside
+    b->a
+    # between sections
+} else {
+}
+autonumber # after an optional token
+"#
+        );
+    }
+
+    #[test]
+    fn keeps_one_blank_line_between_groups() {
+        let data = "\n\na->b\n\n\n\nb->a\nopt {\n\n  a->b\n\n}\n\n";
+        assert_eq!(format(data).unwrap(), "a->b\n\nb->a\nopt {\n    a->b\n}\n");
+    }
+
+    #[test]
+    fn formatting_is_idempotent_and_keeps_the_meaning() {
+        let data = r#"
+        box "Backend" { # services
+          alias s="Server"
+          c
+        }
+        s->*c: "x#y"
+        loop "retry" {
+        c-->>s
+        }
+        ==  sep  ==
+        ...  wait ...
+        "#;
+        let formatted = format(data).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        assert_eq!(
+            diagram(&formatted, &NoIncludes).unwrap().events,
+            diagram(data, &NoIncludes).unwrap().events
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        assert!(format("a->").is_err());
+    }
+}
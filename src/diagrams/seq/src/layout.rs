@@ -2,12 +2,22 @@
 use cassowary::strength::*;
 use core::marker::PhantomData;
 
-use ascii_canvas::{Draw, DrawResult, Rect, TextCanvas};
+use ascii_canvas::{display_width, Draw, DrawError, DrawResult, Rect, TextCanvas};
 use cassowary::WeightedRelation::*;
 use cassowary::{Constraint, Expression, Solver, Variable};
+use diagram_base::{Charset, TransformOptions};
+use std::ops::Range;
 use std::sync::Arc;
 use unicode_segmentation::UnicodeSegmentation;
 
+mod json;
+mod svg;
+
+pub use json::{Bounds, Geometry, MessageEnd, MessageGeometry, ParticipantGeometry};
+
+/// The space between the borders of a box and the participants within it, leaving a blank column
+const BOX_PADDING: f64 = 2.0;
+
 /// Renderable layout node
 /// A common set of variables for a renderable layout element
 struct Vars<T> {
@@ -79,10 +89,18 @@ impl<T: Render<C>, C> Node<T, C> {
     }
 
     fn render<D: Draw>(&self, canvas: &mut D, ctx: &C) -> DrawResult {
-        self.data.render(canvas, &ctx)
+        self.data.render(canvas, ctx)
     }
 }
 
+/// Rounds the width up to the closest even number
+///
+/// This avoids decimals when centering, as we are rendering onto a integer based coordinate system
+/// where decimal positions are not possible to represent.
+fn ceil_even(width: usize) -> usize {
+    (((width as f32) / 2.0).ceil() * 2.0) as usize
+}
+
 trait Render<C> {
     fn render<D: Draw>(&self, canvas: &mut D, ctx: &C) -> DrawResult;
 
@@ -95,52 +113,407 @@ trait Render<C> {
     }
 }
 
+/// Splits the text into the lines it is rendered on
+fn text_lines(text: &str) -> Vec<&str> {
+    text.split('\n').collect()
+}
+
+/// The number of columns occupied by the widest line of the text
+fn text_width(text: &str) -> usize {
+    text_lines(text)
+        .iter()
+        .map(|line| display_width(line))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Breaks each line of the text into lines no wider than the given width, preferring to break
+/// between words. Words that are wider than the limit on their own are split.
+fn wrap(text: &str, max_width: usize) -> Vec<String> {
+    let max_width = max_width.max(1);
+    let mut lines = Vec::new();
+    for line in text_lines(text) {
+        let mut current = String::new();
+        let mut current_len = 0;
+        for word in line.split(' ').filter(|word| !word.is_empty()) {
+            let word_len = display_width(word);
+            if current_len > 0 && current_len + 1 + word_len <= max_width {
+                current.push(' ');
+                current.push_str(word);
+                current_len += 1 + word_len;
+                continue;
+            }
+            if current_len > 0 {
+                lines.push(std::mem::take(&mut current));
+                current_len = 0;
+            }
+
+            // Split the words that would not fit on a line of their own
+            for grapheme in word.graphemes(true) {
+                let grapheme_len = display_width(grapheme);
+                if current_len > 0 && current_len + grapheme_len > max_width {
+                    lines.push(std::mem::take(&mut current));
+                    current_len = 0;
+                }
+                current.push_str(grapheme);
+                current_len += grapheme_len;
+            }
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+/// Pads the text with spaces on the right to make it fill the given width
+fn padded(text: &str, width: usize) -> String {
+    let len = display_width(text);
+    format!("{}{}", text, " ".repeat(width - len))
+}
+
+/// Pads the text with spaces on both sides to center it within the given width
+fn centered(text: &str, width: usize) -> String {
+    let len = display_width(text);
+    let left_padding = (width - len) / 2;
+    format!(
+        "{}{}{}",
+        " ".repeat(left_padding),
+        text,
+        " ".repeat(width - len - left_padding)
+    )
+}
+
+/// Pads the glyph with spaces so that its column `glyph_center` ends up in the middle of the given
+/// width, i.e. right on the lifeline
+fn centered_glyph(glyph: &str, glyph_center: usize, width: usize) -> String {
+    let len = display_width(glyph);
+    let left_padding = width / 2 - glyph_center;
+    format!(
+        "{}{}{}",
+        " ".repeat(left_padding),
+        glyph,
+        " ".repeat(width - len - left_padding)
+    )
+}
+
+/// The characters the elements of a text diagram are drawn with
+struct Glyphs {
+    horizontal: char,
+    vertical: char,
+    top_left: char,
+    top_right: char,
+    bottom_left: char,
+    bottom_right: char,
+    /// Corners of the shapes that are always round, e.g. the lid of a database
+    round_top_left: char,
+    round_top_right: char,
+    round_bottom_left: char,
+    round_bottom_right: char,
+    /// Junctions, named after the direction of the branching line
+    tee_right: char,
+    tee_left: char,
+    tee_down: char,
+    tee_up: char,
+    /// The line of a dashed message
+    dashed: char,
+    /// The line separating the sections of a fragment
+    divider: char,
+    /// The line separating the groups of messages
+    double: char,
+    /// The broken lifeline during a delay
+    dotted: char,
+    arrow_left: char,
+    arrow_right: char,
+    /// The heads of the asynchronous messages
+    open_left: char,
+    open_right: char,
+    cross: char,
+    activation: char,
+}
+
+const UNICODE_GLYPHS: Glyphs = Glyphs {
+    horizontal: '─',
+    vertical: '│',
+    top_left: '┌',
+    top_right: '┐',
+    bottom_left: '└',
+    bottom_right: '┘',
+    round_top_left: '╭',
+    round_top_right: '╮',
+    round_bottom_left: '╰',
+    round_bottom_right: '╯',
+    tee_right: '├',
+    tee_left: '┤',
+    tee_down: '┬',
+    tee_up: '┴',
+    dashed: '-',
+    divider: '╌',
+    double: '═',
+    dotted: '┊',
+    arrow_left: '◀',
+    arrow_right: '▶',
+    open_left: '◁',
+    open_right: '▷',
+    cross: '×',
+    activation: '┃',
+};
+
+const ASCII_GLYPHS: Glyphs = Glyphs {
+    horizontal: '-',
+    vertical: '|',
+    top_left: '+',
+    top_right: '+',
+    bottom_left: '+',
+    bottom_right: '+',
+    round_top_left: '.',
+    round_top_right: '.',
+    round_bottom_left: '\'',
+    round_bottom_right: '\'',
+    tee_right: '+',
+    tee_left: '+',
+    tee_down: '+',
+    tee_up: '+',
+    dashed: '.',
+    divider: '.',
+    double: '=',
+    dotted: ':',
+    arrow_left: '<',
+    arrow_right: '>',
+    open_left: '(',
+    open_right: ')',
+    cross: 'x',
+    activation: '#',
+};
+
+const ROUNDED_GLYPHS: Glyphs = Glyphs {
+    top_left: '╭',
+    top_right: '╮',
+    bottom_left: '╰',
+    bottom_right: '╯',
+    ..UNICODE_GLYPHS
+};
+
+impl Glyphs {
+    fn of(charset: Charset) -> &'static Glyphs {
+        match charset {
+            Charset::Unicode => &UNICODE_GLYPHS,
+            Charset::Ascii => &ASCII_GLYPHS,
+            Charset::Rounded => &ROUNDED_GLYPHS,
+        }
+    }
+
+    /// A horizontal line of the given width
+    fn line(&self, width: usize) -> String {
+        self.horizontal.to_string().repeat(width)
+    }
+
+    /// The arrowheads pointing to the left and to the right
+    fn arrowheads(&self, head: &ArrowHead) -> (char, char) {
+        match head {
+            ArrowHead::Filled => (self.arrow_left, self.arrow_right),
+            ArrowHead::Open => (self.open_left, self.open_right),
+            ArrowHead::Cross => (self.cross, self.cross),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParticipantKind {
+    Participant,
+    Actor,
+    Boundary,
+    Database,
+    Queue,
+}
+
 #[derive(PartialEq)]
 pub struct Participant {
     pub id: String,
     pub name: String,
+    pub kind: ParticipantKind,
 }
 
-struct BareRenderCtx;
+struct BareRenderCtx {
+    glyphs: &'static Glyphs,
+}
 
 impl Render<BareRenderCtx> for Arc<Participant> {
     fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
-        let width = self.name.graphemes(true).count() + 4;
+        let name_len = text_width(&self.name);
+        let width = match self.kind {
+            ParticipantKind::Participant | ParticipantKind::Database => name_len + 4,
+            // Leave space for the double border on the right
+            ParticipantKind::Queue => name_len + 5,
+            // The name is drawn below the glyph
+            ParticipantKind::Actor => (name_len + 2).max(4),
+            ParticipantKind::Boundary => (name_len + 2).max(8),
+        };
 
         // TODO: explore if we can do the rounding in the constraint solver instead
-        // Ensure that the width is divisible by two to avoid decimals as we are rendering onto a
-        // integer based coordinate system where decimals positions are not possible to represent
-        Some((((width as f32) / 2.0).ceil() * 2.0) as usize)
+        Some(ceil_even(width))
     }
 
     fn height(&self, _ctx: &BareRenderCtx) -> Option<usize> {
-        Some(3)
+        let lines = text_lines(&self.name).len();
+        match self.kind {
+            ParticipantKind::Participant | ParticipantKind::Queue => Some(lines + 2),
+            ParticipantKind::Actor | ParticipantKind::Boundary | ParticipantKind::Database => {
+                Some(lines + 3)
+            }
+        }
     }
 
-    fn render<D: Draw>(&self, canvas: &mut D, _ctx: &BareRenderCtx) -> DrawResult {
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
         let Rect { width, .. } = canvas.bounds();
-        let name_len = self.name.graphemes(true).count();
-        let mut repr = String::from("");
-        let segments = [
-            "┌",
-            &"─".repeat(width - 2),
-            "┐",
-            "\n",
-            "│",
-            &" ".repeat(1),
-            &self.name,
-            &" ".repeat(width - name_len - 3),
-            "│",
-            "\n",
-            "└",
-            &"─".repeat(width - 2),
-            "┘",
-        ];
-        for segment in segments.iter() {
-            repr.push_str(segment);
+        let width = *width;
+        let lines = text_lines(&self.name);
+        let g = ctx.glyphs;
+        let mut rows = Vec::new();
+        match self.kind {
+            ParticipantKind::Participant => {
+                rows.push(format!(
+                    "{}{}{}",
+                    g.top_left,
+                    g.line(width - 2),
+                    g.top_right
+                ));
+                for line in lines {
+                    rows.push(format!("{0} {1}{0}", g.vertical, padded(line, width - 3)));
+                }
+                rows.push(format!(
+                    "{}{}{}",
+                    g.bottom_left,
+                    g.line(width - 2),
+                    g.bottom_right
+                ));
+            }
+            ParticipantKind::Actor => {
+                rows.push(centered_glyph(" O ", 1, width));
+                rows.push(centered_glyph("/|\\", 1, width));
+                rows.push(centered_glyph("/ \\", 1, width));
+                rows.extend(lines.iter().map(|line| centered(line, width)));
+            }
+            ParticipantKind::Boundary => {
+                let top = format!(
+                    "{} {}{}{}",
+                    g.vertical,
+                    g.round_top_left,
+                    g.line(3),
+                    g.round_top_right
+                );
+                let middle = format!(
+                    "{}{}{}   {}",
+                    g.tee_right, g.horizontal, g.tee_left, g.vertical
+                );
+                let bottom = format!(
+                    "{} {}{}{}",
+                    g.vertical,
+                    g.round_bottom_left,
+                    g.line(3),
+                    g.round_bottom_right
+                );
+                rows.push(centered_glyph(&top, 4, width));
+                rows.push(centered_glyph(&middle, 4, width));
+                rows.push(centered_glyph(&bottom, 4, width));
+                rows.extend(lines.iter().map(|line| centered(line, width)));
+            }
+            ParticipantKind::Database => {
+                rows.push(format!(
+                    "{}{}{}",
+                    g.round_top_left,
+                    g.line(width - 2),
+                    g.round_top_right
+                ));
+                rows.push(format!(
+                    "{}{}{}",
+                    g.tee_right,
+                    g.line(width - 2),
+                    g.tee_left
+                ));
+                for line in lines {
+                    rows.push(format!("{0} {1}{0}", g.vertical, padded(line, width - 3)));
+                }
+                rows.push(format!(
+                    "{}{}{}",
+                    g.round_bottom_left,
+                    g.line(width - 2),
+                    g.round_bottom_right
+                ));
+            }
+            ParticipantKind::Queue => {
+                rows.push(format!(
+                    "{}{}{}{}",
+                    g.top_left,
+                    g.line(width - 3),
+                    g.tee_down,
+                    g.round_top_right
+                ));
+                for line in lines {
+                    rows.push(format!(
+                        "{0} {1}{0}{0}",
+                        g.vertical,
+                        padded(line, width - 4)
+                    ));
+                }
+                rows.push(format!(
+                    "{}{}{}{}",
+                    g.bottom_left,
+                    g.line(width - 3),
+                    g.tee_up,
+                    g.round_bottom_right
+                ));
+            }
         }
 
-        canvas.draw(0, 0, &repr.split('\n').collect::<Vec<_>>())?;
+        canvas.draw(0, 0, &rows.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+/// A labelled frame around a group of adjacent participants, spanning their lifelines
+pub struct ParticipantBox {
+    pub label: String,
+}
+
+impl ParticipantBox {
+    fn label(&self) -> String {
+        text_lines(&self.label).join(" ")
+    }
+}
+
+impl Render<BareRenderCtx> for ParticipantBox {
+    fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // Reserve enough space for the label embedded in the top border
+        Some(display_width(&self.label()) + 6)
+    }
+
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
+        let Rect { width, height, .. } = canvas.bounds();
+        let (width, height) = (*width, *height);
+        let g = ctx.glyphs;
+
+        if height > 2 {
+            let vertical = g.vertical.to_string();
+            let side = vec![vertical.as_str(); height - 2];
+            canvas.draw(0, 1, &side)?;
+            canvas.draw(width - 1, 1, &side)?;
+        }
+        let label = match self.label() {
+            label if label.is_empty() => g.line(width - 2),
+            label => {
+                let label = format!(" {} ", label);
+                let fill = width - 2 - display_width(&label);
+                format!("{}{}{}", g.line(fill / 2), label, g.line(fill - fill / 2))
+            }
+        };
+        let top = format!("{}{}{}", g.round_top_left, label, g.round_top_right);
+        let bottom = format!(
+            "{}{}{}",
+            g.round_bottom_left,
+            g.line(width - 2),
+            g.round_bottom_right
+        );
+        canvas.draw(0, 0, &[top.as_str()])?;
+        canvas.draw(0, height - 1, &[bottom.as_str()])?;
         Ok(())
     }
 }
@@ -151,77 +524,163 @@ pub enum EdgeStyle {
     Dashed,
 }
 
+/// Change of the activation state caused by a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Activation {
+    /// The recipient becomes active
+    Activate,
+    /// The sender stops being active
+    Deactivate,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArrowHead {
+    Filled,
+    Open,
+    Cross,
+}
+
+/// One end of a message
+#[derive(PartialEq)]
+pub enum Endpoint {
+    Participant(Arc<Participant>),
+    /// The left edge of the diagram
+    LeftBorder,
+    /// The right edge of the diagram
+    RightBorder,
+}
+
 pub struct Message {
-    pub source: Arc<Participant>,
-    pub target: Arc<Participant>,
+    pub source: Endpoint,
+    pub target: Endpoint,
     pub payload: String,
     pub edge_style: EdgeStyle,
+    pub head: ArrowHead,
+    /// Whether the arrow points at the sender as well
+    pub bidirectional: bool,
+    pub activation: Option<Activation>,
+    /// Whether the message creates its target, placing the box of the target next to the arrow
+    /// instead of at the top of the diagram
+    pub create: bool,
+}
+
+impl Message {
+    /// The lines of the payload, wrapped to the maximum label width if there is one
+    fn lines(&self, ctx: &MessageRenderCtx) -> Vec<String> {
+        match ctx.max_label_width {
+            Some(max_width) => wrap(&self.payload, max_width),
+            None => text_lines(&self.payload)
+                .iter()
+                .map(|line| line.to_string())
+                .collect(),
+        }
+    }
+
+    /// The row of the arrow pointing at the recipient relative to the message
+    fn arrow_row(&self, ctx: &MessageRenderCtx) -> usize {
+        let lines = self.lines(ctx).len();
+        match ctx.is_loop() {
+            // The payload is drawn between the outgoing and the returning arrow
+            true => lines + 1,
+            // The payload is drawn above the arrow
+            false => lines,
+        }
+    }
 }
 
 struct MessageRenderCtx {
+    /// The positions of the endpoints from left to right. The left border of the diagram comes
+    /// first, followed by the participants and the right border.
     source_idx: usize,
     target_idx: usize,
+    /// The position of the right border of the diagram
+    right_border_idx: usize,
+    max_label_width: Option<usize>,
+    glyphs: &'static Glyphs,
 }
 
 impl MessageRenderCtx {
     fn is_loop(&self) -> bool {
         self.source_idx == self.target_idx
     }
+
+    fn points_left(&self) -> bool {
+        self.source_idx > self.target_idx
+    }
+
+    /// Whether the message comes from or goes to the left edge of the diagram
+    fn starts_at_left_border(&self) -> bool {
+        self.source_idx.min(self.target_idx) == 0
+    }
+
+    /// Whether the message comes from or goes to the right edge of the diagram
+    fn ends_at_right_border(&self) -> bool {
+        self.source_idx.max(self.target_idx) == self.right_border_idx
+    }
 }
 
 impl Render<MessageRenderCtx> for Message {
     fn width(&self, ctx: &MessageRenderCtx) -> Option<usize> {
-        let len = self.payload.graphemes(true).count();
+        let len = text_width(&self.lines(ctx).join("\n"));
         let width = match ctx.is_loop() {
             // Put the text to the right of the loop arrow + some spacing
             true => len + 3 + 3,
             // Reserve enough space for the arrow + some spacing
             false => len + 4,
         };
-        // Ensure that the width is divisible by two to avoid decimals as we are rendering onto a
-        // integer based coordinate system where decimals positions are not possible to represent
-        Some((((width as f32) / 2.0).ceil() * 2.0) as usize)
+        Some(ceil_even(width))
     }
 
     fn height(&self, ctx: &MessageRenderCtx) -> Option<usize> {
-        match ctx.is_loop() {
-            true => Some(4),
-            false => Some(3),
-        }
+        // Leave an empty row below the arrow
+        Some(self.arrow_row(ctx) + 2)
     }
 
     fn render<D: Draw>(&self, canvas: &mut D, ctx: &MessageRenderCtx) -> DrawResult {
-        let Rect { width, .. } = canvas.bounds();
-        let payload = &self.payload;
-        let len = payload.graphemes(true).count();
+        let width = canvas.bounds().width;
+        let lines = self.lines(ctx);
+        let len = text_width(&lines.join("\n"));
 
         match ctx.is_loop() {
             true => {
-                canvas.draw(0, 0, &["─┐", " │", "◀┘"])?;
+                let g = ctx.glyphs;
+                let (head, _) = g.arrowheads(&self.head);
+                let tail = match self.bidirectional {
+                    true => head,
+                    false => g.horizontal,
+                };
+                let mut arrow = vec![format!("{}{}", tail, g.top_right)];
+                arrow.extend(vec![format!(" {}", g.vertical); lines.len()]);
+                arrow.push(format!("{}{}", head, g.bottom_right));
+                canvas.draw(0, 0, &arrow.iter().map(String::as_str).collect::<Vec<_>>())?;
+
                 let spacer = " ".repeat(len);
-                canvas.draw(3, 0, &[spacer.as_str(), payload.as_str(), spacer.as_str()])?;
+                let mut text = vec![spacer.clone()];
+                text.extend(lines.iter().map(|line| padded(line, len)));
+                text.push(spacer);
+                canvas.draw(3, 0, &text.iter().map(String::as_str).collect::<Vec<_>>())?;
             }
             false => {
-                let left_padding = ((width - len) / 2) as usize;
                 let arrow_char = match self.edge_style {
-                    EdgeStyle::Continuous => '─',
-                    EdgeStyle::Dashed => '-',
+                    EdgeStyle::Continuous => ctx.glyphs.horizontal,
+                    EdgeStyle::Dashed => ctx.glyphs.dashed,
                 };
 
+                let (left_head, right_head) = ctx.glyphs.arrowheads(&self.head);
+                let (first, last) = match (ctx.points_left(), self.bidirectional) {
+                    (_, true) => (left_head, right_head),
+                    (true, false) => (left_head, arrow_char),
+                    (false, false) => (arrow_char, right_head),
+                };
                 let mut arrow = arrow_char.to_string().repeat(width - 2);
-                match ctx.source_idx > ctx.target_idx {
-                    true => {
-                        arrow.push(arrow_char);
-                        arrow.insert(0, '◀');
-                    }
-                    false => {
-                        arrow.insert(0, arrow_char);
-                        arrow.push('▶');
-                    }
-                }
+                arrow.insert(0, first);
+                arrow.push(last);
 
-                canvas.draw(left_padding, 0, &[payload.as_str()])?;
-                canvas.draw(0, 1, &[arrow.as_str()])?;
+                for (row, line) in lines.iter().enumerate() {
+                    let left_padding = (width - display_width(line)) / 2;
+                    canvas.draw(left_padding, row, &[line])?;
+                }
+                canvas.draw(0, lines.len(), &[arrow.as_str()])?;
             }
         }
 
@@ -229,211 +688,1661 @@ impl Render<MessageRenderCtx> for Message {
     }
 }
 
-pub struct Layout {
-    solver: Solver,
-    participants: Vec<Node<Arc<Participant>, BareRenderCtx>>,
-    messages: Vec<Node<Message, MessageRenderCtx>>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotePlacement {
+    Left,
+    Right,
+    Over,
 }
 
-impl Layout {
-    pub fn new() -> Self {
-        Self {
-            solver: Solver::new(),
-            participants: Vec::new(),
-            messages: Vec::new(),
-        }
+pub struct Note {
+    /// The participants the note is anchored to, either one or two (a range) for notes placed
+    /// over the lifelines
+    pub participants: Vec<Arc<Participant>>,
+    pub placement: NotePlacement,
+    pub text: String,
+}
+
+impl Render<BareRenderCtx> for Note {
+    fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        let width = text_width(&self.text) + 4;
+        Some(ceil_even(width))
     }
 
-    pub fn add_participant(&mut self, participant: Arc<Participant>) {
-        let spacing = 1.0;
+    fn height(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // The box + some spacing below it
+        Some(text_lines(&self.text).len() + 3)
+    }
 
-        // Compute the left coordinate for the new participant
-        let right = match self.participants.last() {
-            Some(node) => node.vars.right() + spacing,
-            None => Expression::from(0.0),
-        };
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
+        let Rect { width, .. } = canvas.bounds();
+        let text_len = text_width(&self.text);
+        let g = ctx.glyphs;
+        // The lines are left-aligned within a block centered in the box
+        let left_padding = (width - 2 - text_len) / 2;
+        let right_padding = width - 2 - text_len - left_padding;
+        let border = g.line(width - 2);
+        let mut rows = vec![format!("{}{}{}", g.top_left, border, g.top_right)];
+        for line in text_lines(&self.text) {
+            rows.push(format!(
+                "{0}{1}{2}{3}{0}",
+                g.vertical,
+                " ".repeat(left_padding),
+                padded(line, text_len),
+                " ".repeat(right_padding)
+            ));
+        }
+        rows.push(format!("{}{}{}", g.bottom_left, border, g.bottom_right));
 
-        // Setup the initial constraints
-        let render_ctx = BareRenderCtx {};
-        let vars = Vars::new();
-        let constraints = vec![
-            vars.left | GE(REQUIRED) | right,
-            vars.top | GE(REQUIRED) | 0.0,
-            vars.width
-                | EQ(REQUIRED)
-                | participant
-                    .width(&render_ctx)
-                    .expect("Participant missing width") as f32,
-            vars.height
-                | EQ(REQUIRED)
-                | participant
-                    .height(&render_ctx)
-                    .expect("Participant missing height") as f32,
-        ];
+        canvas.draw(0, 0, &rows.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
 
-        self.participants.push(Node {
-            vars,
-            constraints,
-            data: participant,
-            _phantom: PhantomData,
-        });
+/// A thickened segment of a lifeline during which the participant is active
+struct ActivationBar {
+    /// The index of the active participant
+    participant: usize,
+}
+
+impl Render<BareRenderCtx> for ActivationBar {
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
+        let Rect { height, .. } = canvas.bounds();
+        if *height > 0 {
+            let bar = ctx.glyphs.activation.to_string();
+            canvas.draw(0, 0, &vec![bar.as_str(); *height])?;
+        }
+        Ok(())
     }
+}
+
+/// A line across the whole diagram separating groups of messages
+pub struct Divider {
+    pub label: String,
+}
 
-    pub fn add_message(&mut self, message: Message) {
-        // Fetch the source and target nodes
-        let mut participant_nodes = self.participants.iter_mut();
+impl Render<BareRenderCtx> for Divider {
+    fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // Keep a few glyphs of the line on both sides of the label
+        Some(display_width(&self.label) + 6)
+    }
 
-        // Find the participant to the left of the message
-        let source_or_target =
-            |p: &&mut Node<_, _>| p.data == message.source || p.data == message.target;
-        let left_participant = participant_nodes.find(source_or_target).unwrap();
+    fn height(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // The line + some spacing below it
+        Some(2)
+    }
 
-        // Find the participant to the right of the message. In case of loops, take just the
-        // consecutive participant from the iterator.
-        let right_participant = {
-            let next_node = participant_nodes.next();
-            let target_node = participant_nodes.find(source_or_target);
-            next_node.filter(source_or_target).or(target_node)
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
+        let width = canvas.bounds().width;
+        let label = match self.label.is_empty() {
+            true => String::new(),
+            false => format!(" {} ", self.label),
         };
+        let label_len = display_width(&label);
+        let left = (width - label_len) / 2;
+        let fill = ctx.glyphs.double.to_string();
+        let line = format!(
+            "{}{}{}",
+            fill.repeat(left),
+            label,
+            fill.repeat(width - label_len - left)
+        );
+        canvas.draw(0, 0, &[line.as_str()])?;
+        Ok(())
+    }
+}
 
-        // Compute the top coordinate for the new message
-        let top = self
-            .messages
-            .last()
-            .map(|msg| msg.vars.bottom())
-            .or_else(|| Some(left_participant.vars.bottom()))
-            .unwrap();
+/// A gap in time, drawn by breaking all the lifelines
+pub struct Delay {
+    pub label: String,
+}
 
-        // Construct the render context used to calculate the message with and height
-        let render_ctx = MessageRenderCtx {
-            source_idx: 0,
-            target_idx: right_participant
-                .as_ref()
-                .map(|p| !(left_participant.data == p.data) as usize)
-                .unwrap_or(0),
-        };
+impl Render<BareRenderCtx> for Delay {
+    fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        match self.label.is_empty() {
+            true => Some(0),
+            false => Some(display_width(&self.label) + 2),
+        }
+    }
 
-        // Constraint the new message bounds in relation to the participants
-        let vars = Vars::new();
-        let mut constraints = vec![
-            vars.top | EQ(REQUIRED) | top,
-            vars.left | EQ(REQUIRED) | (left_participant.vars.center() + 1.0),
-            vars.width
-                | GE(REQUIRED)
-                | message.width(&render_ctx).expect("Message missing width") as f64,
-            vars.height
-                | EQ(REQUIRED)
-                | message.height(&render_ctx).expect("Message missing height") as f64,
-        ];
+    fn height(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // The label is drawn between two broken segments of the lifelines
+        match self.label.is_empty() {
+            true => Some(2),
+            false => Some(3),
+        }
+    }
+
+    /// Draws the label, the broken lifelines are drawn by the layout
+    fn render<D: Draw>(&self, canvas: &mut D, _ctx: &BareRenderCtx) -> DrawResult {
+        if !self.label.is_empty() {
+            let width = canvas.bounds().width;
+            let label = format!(" {} ", self.label);
+            let left = (width - display_width(&label)) / 2;
+            canvas.draw(left, 1, &[label.as_str()])?;
+        }
+        Ok(())
+    }
+}
 
-        if let Some(right_participant) = right_participant {
-            // Stretch the message to all the way to the participant on the right
-            constraints.push(vars.right() | EQ(REQUIRED) | right_participant.vars.center());
+#[derive(Debug, Clone, PartialEq)]
+pub enum FragmentKind {
+    Alt,
+    Opt,
+    Loop,
+    Par,
+    Critical,
+    Break,
+}
 
-            // Make space for the new message
-            right_participant
-                .constraints
-                .push(right_participant.vars.center() | GE(REQUIRED) | vars.right());
+impl FragmentKind {
+    fn name(&self) -> &str {
+        match self {
+            FragmentKind::Alt => "alt",
+            FragmentKind::Opt => "opt",
+            FragmentKind::Loop => "loop",
+            FragmentKind::Par => "par",
+            FragmentKind::Critical => "critical",
+            FragmentKind::Break => "break",
         }
+    }
+}
 
-        self.messages.push(Node {
-            vars,
-            constraints,
-            data: message,
-            _phantom: PhantomData,
+pub struct Fragment {
+    kind: FragmentKind,
+    /// The participants involved in the fragment
+    participants: Vec<Arc<Participant>>,
+    /// The guard conditions of the sections
+    sections: Vec<String>,
+    /// The vertical positions of the lines separating the sections
+    dividers: Vec<Variable>,
+}
+
+impl Fragment {
+    pub fn new(
+        kind: FragmentKind,
+        participants: Vec<Arc<Participant>>,
+        sections: Vec<String>,
+    ) -> Self {
+        // Section labels are drawn inline in the frame border
+        let sections = sections
+            .iter()
+            .map(|label| text_lines(label).join(" "))
+            .collect();
+        Self {
+            kind,
+            participants,
+            sections,
+            dividers: Vec::new(),
+        }
+    }
+
+    fn header(&self) -> String {
+        match self.sections.first() {
+            Some(label) if !label.is_empty() => format!("{} [{}]", self.kind.name(), label),
+            _ => self.kind.name().to_string(),
+        }
+    }
+
+    fn labels(&self) -> Vec<String> {
+        let mut labels = vec![self.header()];
+        self.sections.iter().skip(1).for_each(|label| {
+            labels.push(match label.is_empty() {
+                true => String::new(),
+                false => format!("[{}]", label),
+            })
         });
+        labels
     }
+}
 
-    /// Renders the layout and consumes itself.
-    pub fn render(mut self) -> String {
-        self.solver
-            .add_constraints(
-                self.participants
-                    .iter()
-                    .flat_map(|node| &node.constraints)
-                    .collect::<Vec<&Constraint>>(),
-            )
-            .expect("Could not add the constraints");
-        self.solver
-            .add_constraints(
-                self.messages
-                    .iter()
-                    .flat_map(|node| &node.constraints)
-                    .collect::<Vec<&Constraint>>(),
-            )
-            .expect("Could not add the constraints");
+struct FragmentRenderCtx {
+    /// The rows of the section dividers relative to the fragment
+    dividers: Vec<usize>,
+    glyphs: &'static Glyphs,
+}
 
-        // Compute the required canvas size
-        let max_right = self
-            .participants
-            .iter()
-            .map(|p| p.coords(&self.solver).right())
-            .chain(self.messages.iter().map(|p| p.coords(&self.solver).right()))
-            .max()
-            .map(|right| right + 1)
-            .unwrap_or(0);
-        let max_bottom = self
-            .participants
+/// Renders a horizontal line of the fragment frame with the label embedded in it
+fn frame_border(left: char, fill: char, label: &str, right: char, width: usize) -> String {
+    let label = match label.is_empty() {
+        true => String::new(),
+        false => format!(" {} ", label),
+    };
+    let label_len = display_width(&label);
+    let mut border = String::new();
+    border.push(left);
+    border.push(fill);
+    border.push_str(&label);
+    border.push_str(&fill.to_string().repeat(width - 3 - label_len));
+    border.push(right);
+    border
+}
+
+impl Render<FragmentRenderCtx> for Fragment {
+    fn width(&self, _ctx: &FragmentRenderCtx) -> Option<usize> {
+        // Reserve enough space for the labels embedded in the borders
+        self.labels()
             .iter()
-            .map(|p| p.coords(&self.solver).bottom())
-            .chain(
-                self.messages
-                    .iter()
-                    .map(|p| p.coords(&self.solver).bottom()),
-            )
+            .map(|label| display_width(label) + 5)
             .max()
-            .map(|bottom| bottom + 3)
-            .unwrap_or(0);
+    }
 
-        // Render the layout
-        let bare_ctx = BareRenderCtx {};
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &FragmentRenderCtx) -> DrawResult {
+        let Rect { width, height, .. } = canvas.bounds();
+        let (width, height) = (*width, *height);
+        let labels = self.labels();
+        let g = ctx.glyphs;
 
-        // Setup the canvas
-        let mut canvas = TextCanvas::new(max_right, max_bottom);
+        if height > 2 {
+            let vertical = g.vertical.to_string();
+            let side = vec![vertical.as_str(); height - 2];
+            canvas.draw(0, 1, &side)?;
+            canvas.draw(width - 1, 1, &side)?;
+        }
+        let header = frame_border(g.top_left, g.horizontal, &labels[0], g.top_right, width);
+        canvas.draw(0, 0, &[header.as_str()])?;
+        for (divider, label) in ctx.dividers.iter().zip(labels.iter().skip(1)) {
+            let line = frame_border(g.tee_right, g.divider, label, g.tee_left, width);
+            canvas.draw(0, *divider, &[line.as_str()])?;
+        }
+        let footer = frame_border(g.bottom_left, g.horizontal, "", g.bottom_right, width);
+        canvas.draw(0, height - 1, &[footer.as_str()])?;
+        Ok(())
+    }
+}
+
+/// A frame over the lifelines standing for an interaction described elsewhere
+pub struct Reference {
+    /// The participants whose lifelines the frame spans
+    pub participants: Vec<Arc<Participant>>,
+    pub label: String,
+}
+
+/// The tag in the top border of a reference frame
+const REFERENCE_TAG: &str = "ref";
+
+impl Render<BareRenderCtx> for Reference {
+    fn width(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // Keep a blank column on both sides of the label, and leave space for the tag
+        let width = (text_width(&self.label) + 6).max(display_width(REFERENCE_TAG) + 5);
+        Some(ceil_even(width))
+    }
+
+    fn height(&self, _ctx: &BareRenderCtx) -> Option<usize> {
+        // The frame + some spacing below it
+        Some(text_lines(&self.label).len() + 3)
+    }
+
+    fn render<D: Draw>(&self, canvas: &mut D, ctx: &BareRenderCtx) -> DrawResult {
+        let Rect { width, .. } = canvas.bounds();
+        let width = *width;
+        let g = ctx.glyphs;
+        // The frame hides the lifelines it spans
+        let mut rows = vec![frame_border(
+            g.top_left,
+            g.horizontal,
+            REFERENCE_TAG,
+            g.top_right,
+            width,
+        )];
+        for line in text_lines(&self.label) {
+            rows.push(format!("{0}{1}{0}", g.vertical, centered(line, width - 2)));
+        }
+        rows.push(format!(
+            "{}{}{}",
+            g.bottom_left,
+            g.line(width - 2),
+            g.bottom_right
+        ));
+
+        canvas.draw(0, 0, &rows.iter().map(String::as_str).collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    /// The constraints of the elements contradict each other
+    UnsatisfiableConstraints,
+    /// An element does not fit within the area computed for it
+    DrawFailed(DrawError),
+}
+
+impl From<DrawError> for LayoutError {
+    fn from(err: DrawError) -> LayoutError {
+        LayoutError::DrawFailed(err)
+    }
+}
+
+/// The part of the diagram a caption line belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptionKind {
+    Header,
+    Title,
+    Footer,
+}
+
+/// A line of the header, title or footer, centered over the whole diagram
+struct Caption<'a> {
+    row: usize,
+    text: &'a str,
+    kind: CaptionKind,
+}
+
+/// The size of the solved layout
+struct Extent {
+    width: usize,
+    height: usize,
+    /// The row where the mirrored participants start
+    foot_top: usize,
+    /// The row right below the mirrored participants
+    foot_bottom: usize,
+}
+
+/// The part of the diagram a participant exists in
+#[derive(Default)]
+struct Lifespan {
+    /// Whether the next message sent to the participant creates it
+    pending_creation: bool,
+    /// Whether the participant is created by a message instead of existing from the start
+    created: bool,
+    /// The row the lifeline ends at in case the participant is destroyed
+    destroyed: Option<Variable>,
+}
+
+pub struct Layout {
+    options: TransformOptions,
+    solver: Solver,
+    participants: Vec<Node<Arc<Participant>, BareRenderCtx>>,
+    /// The creation and the destruction of each of the participants
+    lifespans: Vec<Lifespan>,
+    /// The row where the lifelines start, right below the tallest participant
+    lifeline_top: Variable,
+    /// The column the messages going to the outside of the diagram end at
+    right_border: Variable,
+    messages: Vec<Node<Message, MessageRenderCtx>>,
+    notes: Vec<Node<Note, BareRenderCtx>>,
+    references: Vec<Node<Reference, BareRenderCtx>>,
+    fragments: Vec<Node<Fragment, FragmentRenderCtx>>,
+    activations: Vec<Node<ActivationBar, BareRenderCtx>>,
+    dividers: Vec<Node<Divider, BareRenderCtx>>,
+    delays: Vec<Node<Delay, BareRenderCtx>>,
+    boxes: Vec<Node<ParticipantBox, BareRenderCtx>>,
+    /// Index of the box enclosing the newly added participants
+    open_box: Option<usize>,
+    /// Index of the first participant added to the open box
+    open_box_start: usize,
+    /// Indices of the fragments that are currently enclosing the newly added elements
+    open_fragments: Vec<usize>,
+    /// Stack of the activations that have not been ended yet, as (participant index, activation
+    /// index) pairs
+    open_activations: Vec<(usize, usize)>,
+    /// The bottom edge of the most recently stacked element
+    cursor: Option<Expression>,
+    /// The row of the arrow in case the most recently stacked element is a message
+    last_arrow: Option<Expression>,
+    title: Option<String>,
+    header: Option<String>,
+    footer: Option<String>,
+}
+
+impl Layout {
+    pub fn new(options: TransformOptions) -> Self {
+        Self {
+            options,
+            solver: Solver::new(),
+            participants: Vec::new(),
+            lifespans: Vec::new(),
+            lifeline_top: Variable::new(),
+            right_border: Variable::new(),
+            messages: Vec::new(),
+            notes: Vec::new(),
+            references: Vec::new(),
+            fragments: Vec::new(),
+            activations: Vec::new(),
+            dividers: Vec::new(),
+            delays: Vec::new(),
+            boxes: Vec::new(),
+            open_box: None,
+            open_box_start: 0,
+            open_fragments: Vec::new(),
+            open_activations: Vec::new(),
+            cursor: None,
+            last_arrow: None,
+            title: None,
+            header: None,
+            footer: None,
+        }
+    }
+
+    /// Sets the caption drawn above the participants
+    pub fn set_title(&mut self, title: String) {
+        self.title = Some(title);
+    }
+
+    /// Sets the text drawn at the very top of the diagram
+    pub fn set_header(&mut self, header: String) {
+        self.header = Some(header);
+    }
+
+    /// Sets the text drawn at the very bottom of the diagram
+    pub fn set_footer(&mut self, footer: String) {
+        self.footer = Some(footer);
+    }
+
+    /// The captions above the participants, in the order they are drawn
+    fn heading(&self) -> Vec<(&str, CaptionKind)> {
+        let header = self.header.as_deref().map(|h| (h, CaptionKind::Header));
+        let title = self.title.as_deref().map(|t| (t, CaptionKind::Title));
+        header.into_iter().chain(title).collect()
+    }
+
+    /// The number of rows above the participants, each caption is followed by an empty row
+    fn heading_height(&self) -> usize {
+        self.heading()
+            .iter()
+            .map(|(text, _)| text_lines(text).len() + 1)
+            .sum()
+    }
+
+    /// The number of rows below the mirrored participants, the footer is preceded by an empty row
+    fn footing_height(&self) -> usize {
+        self.footer
+            .as_deref()
+            .map(|footer| text_lines(footer).len() + 1)
+            .unwrap_or(0)
+    }
+
+    /// The number of rows taken by the borders of the boxes above and below the participants
+    fn frame_height(&self) -> usize {
+        match self.boxes.is_empty() {
+            true => 0,
+            false => 1,
+        }
+    }
+
+    /// The lines of all the captions together with the rows they are drawn on
+    fn captions(&self, extent: &Extent) -> Vec<Caption<'_>> {
+        let mut captions = Vec::new();
+        let mut row = 0;
+        for (text, kind) in self.heading() {
+            for line in text_lines(text) {
+                captions.push(Caption {
+                    row,
+                    text: line,
+                    kind,
+                });
+                row += 1;
+            }
+            row += 1;
+        }
+        if let Some(footer) = &self.footer {
+            let lines = text_lines(footer);
+            let top = extent.height - lines.len();
+            captions.extend(lines.into_iter().enumerate().map(|(idx, line)| Caption {
+                row: top + idx,
+                text: line,
+                kind: CaptionKind::Footer,
+            }));
+        }
+        captions
+    }
+
+    /// Moves the cursor below the most recently stacked element
+    fn advance(&mut self, bottom: Expression) {
+        self.cursor = Some(bottom);
+        self.last_arrow = None;
+    }
+
+    /// The number of activations of the participant that have not been ended yet
+    fn activation_depth(&self, participant: usize) -> usize {
+        self.open_activations
+            .iter()
+            .filter(|(p, _)| *p == participant)
+            .count()
+    }
+
+    fn open_activation(&mut self, participant: usize, top: Expression) {
+        // Stack the nested activations next to each other
+        let level = self.activation_depth(participant);
+        let vars = Vars::new();
+        let center = self.participants[participant].vars.center();
+        let constraints = vec![
+            vars.top | EQ(REQUIRED) | top,
+            vars.left | EQ(REQUIRED) | (center + level as f64),
+            vars.width | EQ(REQUIRED) | 1.0,
+        ];
+
+        self.open_activations
+            .push((participant, self.activations.len()));
+        self.activations.push(Node {
+            vars,
+            constraints,
+            data: ActivationBar { participant },
+            _phantom: PhantomData,
+        });
+    }
+
+    fn close_activation(&mut self, participant: usize, bottom: Expression) {
+        let position = self
+            .open_activations
+            .iter()
+            .rposition(|(p, _)| *p == participant);
+        if let Some(position) = position {
+            let (_, index) = self.open_activations.remove(position);
+            let node = &mut self.activations[index];
+            node.constraints
+                .push(node.vars.bottom() | EQ(REQUIRED) | (bottom + 1.0));
+        }
+    }
+
+    /// Starts an activation of the participant at the arrow of the most recent message, or below
+    /// the most recently stacked element if it is not a message
+    pub fn activate(&mut self, participant: &Arc<Participant>) {
+        let index = self.participant_index(participant);
+        let top = self
+            .last_arrow
+            .clone()
+            .or_else(|| self.cursor.clone())
+            .unwrap_or_else(|| Expression::from(self.lifeline_top));
+        self.open_activation(index, top);
+    }
+
+    /// Ends the most recent activation of the participant at the arrow of the most recent message,
+    /// or at the bottom of the most recently stacked element if it is not a message
+    pub fn deactivate(&mut self, participant: &Arc<Participant>) {
+        let index = self.participant_index(participant);
+        let bottom = self
+            .last_arrow
+            .clone()
+            .or_else(|| self.cursor.clone().map(|cursor| cursor - 1.0))
+            .unwrap_or_else(|| Expression::from(self.lifeline_top));
+        self.close_activation(index, bottom);
+    }
+
+    /// Marks the participant to be created by the next message sent to it
+    pub fn create(&mut self, participant: &Arc<Participant>) {
+        let index = self.participant_index(participant);
+        self.lifespans[index].pending_creation = true;
+    }
+
+    /// Ends the lifeline of the participant at the arrow of the most recent message, or on a row
+    /// of its own if the most recently stacked element is not a message or the message creates
+    /// the participant
+    pub fn destroy(&mut self, participant: &Arc<Participant>) {
+        let index = self.participant_index(participant);
+        if self.lifespans[index].destroyed.is_some() {
+            return;
+        }
+        let created_by_last_message = self.last_arrow.is_some()
+            && self.messages.last().is_some_and(|node| {
+                node.data.create
+                    && self.participant_at(self.endpoint_index(&node.data.target)) == Some(index)
+            });
+        let row = match &self.last_arrow {
+            Some(arrow) if !created_by_last_message => arrow.clone(),
+            // Below the box of the created participant, or the header of the enclosing fragment
+            _ => {
+                let row = self.next_top();
+                self.advance(row.clone() + 1.0);
+                row
+            }
+        };
+        while self.activation_depth(index) > 0 {
+            self.close_activation(index, row.clone());
+        }
+
+        let end = Variable::new();
+        let node = &mut self.participants[index];
+        node.constraints.push(end | EQ(REQUIRED) | row);
+        self.lifespans[index].destroyed = Some(end);
+    }
+
+    /// The rows the lifeline of the participant spans, from the bottom of its box to the row it
+    /// is destroyed at or to the mirrored participants
+    fn lifeline_rows(&self, index: usize, extent: &Extent) -> Range<usize> {
+        let start = self.participants[index].coords(&self.solver).bottom();
+        let end = self.destroyed_at(index).unwrap_or(extent.foot_top);
+        start..end.max(start)
+    }
+
+    /// The area of the activation bar cut to the lifeline of its participant, none if nothing of
+    /// it is left, e.g. when the participant is destroyed before any message
+    fn activation_coords(
+        &self,
+        node: &Node<ActivationBar, BareRenderCtx>,
+        extent: &Extent,
+    ) -> Option<Vars<usize>> {
+        let coords = node.coords(&self.solver);
+        let rows = self.lifeline_rows(node.data.participant, extent);
+        let top = coords.top.max(rows.start);
+        let bottom = coords.bottom().min(rows.end);
+        match top < bottom {
+            true => Some(Vars {
+                top,
+                height: bottom - top,
+                ..coords
+            }),
+            false => None,
+        }
+    }
+
+    /// The left edge and the width of the area the divider is drawn across, either the inside of
+    /// the innermost fragment enclosing it or the whole diagram
+    fn divider_span(&self, coords: &Vars<usize>, extent: &Extent) -> (usize, usize) {
+        self.fragments
+            .iter()
+            .map(|node| node.coords(&self.solver))
+            .filter(|fragment| fragment.top < coords.top && coords.bottom() <= fragment.bottom())
+            .min_by_key(|fragment| fragment.width)
+            .map(|fragment| (fragment.left + 1, fragment.width - 2))
+            .unwrap_or((0, extent.width))
+    }
+
+    /// The row the lifeline of the participant ends at, if it is destroyed
+    fn destroyed_at(&self, index: usize) -> Option<usize> {
+        self.lifespans[index]
+            .destroyed
+            .map(|end| self.solver.get_value(end) as usize)
+    }
+
+    /// The position of the endpoint from left to right, the borders of the diagram included
+    fn endpoint_index(&self, endpoint: &Endpoint) -> usize {
+        match endpoint {
+            Endpoint::LeftBorder => 0,
+            Endpoint::Participant(participant) => self.participant_index(participant) + 1,
+            Endpoint::RightBorder => self.participants.len() + 1,
+        }
+    }
+
+    /// The index of the participant at the position, unless it is one of the borders
+    fn participant_at(&self, endpoint_idx: usize) -> Option<usize> {
+        match endpoint_idx {
+            0 => None,
+            idx if idx > self.participants.len() => None,
+            idx => Some(idx - 1),
+        }
+    }
+
+    fn participant_index(&self, participant: &Arc<Participant>) -> usize {
+        self.participants
+            .iter()
+            .position(|p| &p.data == participant)
+            .expect("Unknown participant")
+    }
+
+    /// Returns the indices of the leftmost and the rightmost of the given participants
+    fn participant_range(&self, participants: &[Arc<Participant>]) -> (usize, usize) {
+        let indices = participants
+            .iter()
+            .map(|p| self.participant_index(p))
+            .collect::<Vec<_>>();
+        (
+            *indices.iter().min().expect("No participants"),
+            *indices.iter().max().expect("No participants"),
+        )
+    }
+
+    /// Computes the top coordinate for a new element. Keep a gap between the element and the
+    /// participant boxes if it is the first element in the diagram.
+    fn next_top(&self) -> Expression {
+        self.cursor
+            .clone()
+            .unwrap_or_else(|| self.lifeline_top + 1.0)
+    }
+
+    /// Constraints ensuring that the element is enclosed by all the open fragments
+    fn enclose(&self, vars: &Vars<Variable>) -> Vec<Constraint> {
+        // Keep a blank column between the element and the frame borders
+        let margin = 2.0;
+        self.open_fragments
+            .iter()
+            .flat_map(|&index| {
+                let fragment = &self.fragments[index].vars;
+                vec![
+                    (fragment.left + margin) | LE(REQUIRED) | vars.left,
+                    fragment.right() | GE(REQUIRED) | (vars.right() + margin),
+                ]
+            })
+            .collect()
+    }
+
+    /// The right edges of the most recently added participant and the most recently closed box,
+    /// the next participant or box is placed to the right of them
+    fn head_row_right(&self) -> Vec<Expression> {
+        let participant = self.participants.last().map(|node| node.vars.right());
+        let closed_box = match self.open_box {
+            Some(_) => None,
+            None => self.boxes.last().map(|node| node.vars.right()),
+        };
+        participant.into_iter().chain(closed_box).collect()
+    }
+
+    /// Opens a new box enclosing all the participants added until it is ended
+    pub fn begin_box(&mut self, participant_box: ParticipantBox) {
+        let spacing = 1.0;
+
+        let render_ctx = self.bare_ctx();
+        let vars = Vars::new();
+        let mut constraints = vec![
+            vars.left | GE(REQUIRED) | 0.0,
+            vars.width
+                | GE(REQUIRED)
+                | participant_box
+                    .width(&render_ctx)
+                    .expect("Box missing width") as f64,
+        ];
+        constraints.extend(
+            self.head_row_right()
+                .into_iter()
+                .map(|right| vars.left | GE(REQUIRED) | (right + spacing)),
+        );
+
+        self.open_box = Some(self.boxes.len());
+        self.open_box_start = self.participants.len();
+        self.boxes.push(Node {
+            vars,
+            constraints,
+            data: participant_box,
+            _phantom: PhantomData,
+        });
+    }
+
+    /// Closes the open box
+    pub fn end_box(&mut self) {
+        let index = self.open_box.take().expect("No open box");
+        // The last participant is placed right before the right border of the box
+        if self.participants.len() > self.open_box_start {
+            let last = self.participants.last().unwrap().vars.right();
+            let node = &mut self.boxes[index];
+            node.constraints
+                .push(node.vars.right() | EQ(REQUIRED) | (last + BOX_PADDING));
+        }
+    }
+
+    pub fn add_participant(&mut self, participant: Arc<Participant>) {
+        let spacing = 1.0;
+
+        // Setup the initial constraints
+        let render_ctx = self.bare_ctx();
+        let vars = Vars::new();
+        let mut constraints = vec![
+            vars.left | GE(REQUIRED) | 0.0,
+            vars.top | GE(REQUIRED) | 0.0,
+            vars.width
+                | EQ(REQUIRED)
+                | participant
+                    .width(&render_ctx)
+                    .expect("Participant missing width") as f32,
+            vars.height
+                | EQ(REQUIRED)
+                | participant
+                    .height(&render_ctx)
+                    .expect("Participant missing height") as f32,
+        ];
+        constraints.extend(
+            self.head_row_right()
+                .into_iter()
+                .map(|right| vars.left | GE(REQUIRED) | (right + spacing)),
+        );
+
+        // The first participant of a box is placed right after its left border. Both of the
+        // borders are pinned to the participants, as the tie breakers would otherwise trade the
+        // position of the box against its width.
+        if let Some(index) = self.open_box {
+            if self.participants.len() == self.open_box_start {
+                let node = &self.boxes[index];
+                constraints.push(vars.left | EQ(REQUIRED) | (node.vars.left + BOX_PADDING));
+            }
+        }
+
+        self.participants.push(Node {
+            vars,
+            constraints,
+            data: participant,
+            _phantom: PhantomData,
+        });
+        self.lifespans.push(Lifespan::default());
+    }
+
+    pub fn add_message(&mut self, mut message: Message) {
+        // Construct the render context used to calculate the message with and height
+        let render_ctx = self.message_ctx(&message);
+
+        // Find the participants on the left and the right side of the message, if it does not
+        // come from or go to the outside of the diagram
+        let source_idx = self.participant_at(render_ctx.source_idx);
+        let target_idx = self.participant_at(render_ctx.target_idx);
+        let (left_idx, right_idx) = match render_ctx.points_left() {
+            true => (target_idx, source_idx),
+            false => (source_idx, target_idx),
+        };
+
+        // Find the participant created by the message, a participant can only be created once
+        let created_idx = match target_idx {
+            Some(target_idx) if !render_ctx.is_loop() && !self.lifespans[target_idx].created => {
+                let lifespan = &mut self.lifespans[target_idx];
+                let pending = std::mem::take(&mut lifespan.pending_creation);
+                match message.create || pending {
+                    true => Some(target_idx),
+                    false => None,
+                }
+            }
+            _ => None,
+        };
+        message.create = created_idx.is_some();
+
+        // Compute the top coordinate for the new message
+        let top = self
+            .cursor
+            .clone()
+            .unwrap_or_else(|| Expression::from(self.lifeline_top));
+
+        // Start the message at the edge of the activation bars of the participant on the left,
+        // including the activation started by the message itself unless it is a loop. The
+        // messages coming from the outside start at the left edge of the diagram, and the ones
+        // creating the participant on the left at the edge of its box.
+        let left = match left_idx {
+            Some(left_idx) if created_idx == Some(left_idx) => {
+                self.participants[left_idx].vars.right()
+            }
+            Some(left_idx) => {
+                let mut depth = self.activation_depth(left_idx);
+                if message.activation == Some(Activation::Activate)
+                    && target_idx == Some(left_idx)
+                    && !render_ctx.is_loop()
+                {
+                    depth += 1;
+                }
+                let offset = depth.max(1) as f64;
+                self.participants[left_idx].vars.center() + offset
+            }
+            None => Expression::from(0.0),
+        };
+
+        // Constraint the new message bounds in relation to the participants
+        let vars = Vars::new();
+        let height = message.height(&render_ctx).expect("Message missing height") as f64;
+        let mut constraints = vec![
+            vars.left | EQ(REQUIRED) | left,
+            vars.width
+                | GE(REQUIRED)
+                | message.width(&render_ctx).expect("Message missing width") as f64,
+        ];
+        let arrow = vars.top + message.arrow_row(&render_ctx) as f64;
+        match created_idx {
+            // Center the box of the created participant on the arrow. The box may be taller than
+            // the message, so let it push the message down and stretch it.
+            Some(created_idx) => {
+                let created = &self.participants[created_idx];
+                let height_above_arrow = created
+                    .data
+                    .height(&self.bare_ctx())
+                    .expect("Participant missing height")
+                    .saturating_sub(1)
+                    / 2;
+                constraints.extend(vec![
+                    vars.top | GE(REQUIRED) | top.clone(),
+                    vars.top | EQ(STRONG) | top,
+                    vars.height | GE(REQUIRED) | height,
+                    vars.height | EQ(WEAK) | height,
+                    (created.vars.top + height_above_arrow as f64) | EQ(REQUIRED) | arrow.clone(),
+                    created.vars.top | GE(REQUIRED) | vars.top,
+                    vars.bottom() | GE(REQUIRED) | created.vars.bottom(),
+                ]);
+                constraints.extend(self.enclose(&created.vars));
+                self.lifespans[created_idx].created = true;
+            }
+            None => constraints.extend(vec![
+                vars.top | EQ(REQUIRED) | top,
+                vars.height | EQ(REQUIRED) | height,
+            ]),
+        }
+
+        match right_idx {
+            Some(right_idx) if render_ctx.is_loop() => {
+                if let Some(next_participant) = self.participants.get_mut(right_idx + 1) {
+                    // Make space for the loop
+                    next_participant
+                        .constraints
+                        .push(next_participant.vars.center() | GE(REQUIRED) | vars.right());
+                }
+            }
+            // End the message at the edge of the box of the participant it creates
+            Some(right_idx) if created_idx == Some(right_idx) => constraints
+                .push(vars.right() | EQ(REQUIRED) | self.participants[right_idx].vars.left),
+            Some(right_idx) => {
+                let right_participant = &mut self.participants[right_idx];
+
+                // Stretch the message to all the way to the participant on the right
+                constraints.push(vars.right() | EQ(REQUIRED) | right_participant.vars.center());
+
+                // Make space for the new message
+                right_participant
+                    .constraints
+                    .push(right_participant.vars.center() | GE(REQUIRED) | vars.right());
+            }
+            // Stretch the message leaving the diagram all the way to its right edge
+            None => constraints.push(vars.right() | EQ(REQUIRED) | self.right_border),
+        }
+        constraints.extend(self.enclose(&vars));
+
+        // Update the activations at the row of the arrow. The activation of a created participant
+        // starts right below its box.
+        match (&message.activation, source_idx, target_idx) {
+            (Some(Activation::Activate), _, Some(target_idx)) => {
+                let top = match created_idx {
+                    Some(_) => self.participants[target_idx].vars.bottom(),
+                    None => arrow.clone(),
+                };
+                self.open_activation(target_idx, top)
+            }
+            (Some(Activation::Deactivate), Some(source_idx), _) => {
+                self.close_activation(source_idx, arrow.clone())
+            }
+            _ => (),
+        }
+
+        self.advance(vars.bottom());
+        self.last_arrow = Some(arrow);
+        self.messages.push(Node {
+            vars,
+            constraints,
+            data: message,
+            _phantom: PhantomData,
+        });
+    }
+
+    pub fn add_note(&mut self, note: Note) {
+        let spacing = 1.0;
+
+        // Find the leftmost and the rightmost participant the note is anchored to
+        let (first, last) = self.participant_range(&note.participants);
+        let top = self.next_top();
+
+        // Constraint the new note bounds in relation to the participants it is anchored to
+        let render_ctx = self.bare_ctx();
+        let width = note.width(&render_ctx).expect("Note missing width") as f64;
+        let vars = Vars::new();
+        let mut constraints = vec![
+            vars.top | EQ(REQUIRED) | top,
+            vars.left | GE(REQUIRED) | 0.0,
+            vars.height
+                | EQ(REQUIRED)
+                | note.height(&render_ctx).expect("Note missing height") as f64,
+        ];
+        let first_participant = &self.participants[first];
+        let last_participant = &self.participants[last];
+        match note.placement {
+            NotePlacement::Left => {
+                constraints.push(vars.width | EQ(REQUIRED) | width);
+                constraints.push(
+                    (vars.right() + spacing) | EQ(REQUIRED) | first_participant.vars.center(),
+                );
+            }
+            NotePlacement::Right => {
+                constraints.push(vars.width | EQ(REQUIRED) | width);
+                constraints.push(
+                    vars.left | EQ(REQUIRED) | (first_participant.vars.center() + 1.0 + spacing),
+                );
+            }
+            NotePlacement::Over if first == last => {
+                constraints.push(vars.width | EQ(REQUIRED) | width);
+                constraints.push(vars.center() | EQ(REQUIRED) | first_participant.vars.center());
+            }
+            NotePlacement::Over => {
+                // Stretch the note over all the lifelines in the range
+                constraints.push(vars.width | GE(REQUIRED) | width);
+                constraints
+                    .push((vars.left + 2.0) | EQ(REQUIRED) | first_participant.vars.center());
+                constraints
+                    .push(vars.right() | EQ(REQUIRED) | (last_participant.vars.center() + 3.0));
+            }
+        }
+        constraints.extend(self.enclose(&vars));
+
+        // Make space for the new note between the neighbouring lifelines
+        if first > 0 {
+            let previous = &mut self.participants[first - 1];
+            previous
+                .constraints
+                .push(vars.left | GE(REQUIRED) | (previous.vars.center() + spacing));
+        }
+        if let Some(next) = self.participants.get_mut(last + 1) {
+            next.constraints
+                .push(next.vars.center() | GE(REQUIRED) | (vars.right() + spacing));
+        }
+
+        self.advance(vars.bottom());
+        self.notes.push(Node {
+            vars,
+            constraints,
+            data: note,
+            _phantom: PhantomData,
+        });
+    }
+
+    pub fn add_reference(&mut self, reference: Reference) {
+        let spacing = 1.0;
+        let padding = 2.0;
+
+        // Find the leftmost and the rightmost participant the frame spans
+        let (first, last) = self.participant_range(&reference.participants);
+        let top = self.next_top();
+
+        // Constraint the new frame bounds in relation to the lifelines it spans
+        let render_ctx = self.bare_ctx();
+        let vars = Vars::new();
+        let mut constraints = vec![
+            vars.top | EQ(REQUIRED) | top,
+            vars.left | GE(REQUIRED) | 0.0,
+            vars.width
+                | GE(REQUIRED)
+                | reference
+                    .width(&render_ctx)
+                    .expect("Reference missing width") as f64,
+            vars.height
+                | EQ(REQUIRED)
+                | reference
+                    .height(&render_ctx)
+                    .expect("Reference missing height") as f64,
+        ];
+        let first_participant = &self.participants[first];
+        let last_participant = &self.participants[last];
+        match first == last {
+            true => {
+                constraints.push(vars.center() | EQ(REQUIRED) | first_participant.vars.center())
+            }
+            false => {
+                constraints
+                    .push((vars.left + padding) | EQ(REQUIRED) | first_participant.vars.center());
+                constraints.push(
+                    vars.right() | EQ(REQUIRED) | (last_participant.vars.center() + padding + 1.0),
+                );
+            }
+        }
+        constraints.extend(self.enclose(&vars));
+
+        // Keep the lifelines of the participants that are not spanned outside of the frame
+        if first > 0 {
+            let previous = &mut self.participants[first - 1];
+            previous
+                .constraints
+                .push(vars.left | GE(REQUIRED) | (previous.vars.center() + spacing));
+        }
+        if let Some(next) = self.participants.get_mut(last + 1) {
+            next.constraints
+                .push(next.vars.center() | GE(REQUIRED) | (vars.right() + spacing));
+        }
+
+        self.advance(vars.bottom());
+        self.references.push(Node {
+            vars,
+            constraints,
+            data: reference,
+            _phantom: PhantomData,
+        });
+    }
+
+    /// Stacks an element that spans the whole width of the diagram, drawing over the lifelines
+    fn stack_full_width<T: Render<BareRenderCtx>>(
+        &mut self,
+        element: &T,
+    ) -> (Vars<Variable>, Vec<Constraint>) {
+        let render_ctx = self.bare_ctx();
+        let top = self.next_top();
+        let vars = Vars::new();
+        // The left edge comes last, so that it can be replaced for the nested elements
+        let constraints = vec![
+            vars.top | EQ(REQUIRED) | top,
+            vars.width | GE(REQUIRED) | element.width(&render_ctx).unwrap_or(0) as f64,
+            vars.height | EQ(REQUIRED) | element.height(&render_ctx).unwrap_or(0) as f64,
+            vars.left | EQ(REQUIRED) | 0.0,
+        ];
+        self.advance(vars.bottom());
+        (vars, constraints)
+    }
+
+    pub fn add_divider(&mut self, divider: Divider) {
+        let (vars, mut constraints) = self.stack_full_width(&divider);
+        // Within a fragment the divider only spans the frame, which is kept wide enough for the
+        // label
+        if !self.open_fragments.is_empty() {
+            constraints.pop();
+            constraints.extend(self.enclose(&vars));
+        }
+        self.dividers.push(Node {
+            vars,
+            constraints,
+            data: divider,
+            _phantom: PhantomData,
+        });
+    }
+
+    pub fn add_delay(&mut self, delay: Delay) {
+        let (vars, constraints) = self.stack_full_width(&delay);
+        self.delays.push(Node {
+            vars,
+            constraints,
+            data: delay,
+            _phantom: PhantomData,
+        });
+    }
+
+    /// Opens a new fragment enclosing all the elements added until it is ended
+    pub fn begin_fragment(&mut self, fragment: Fragment) {
+        let spacing = 1.0;
+        let padding = 2.0;
+
+        let (first, last) = self.participant_range(&fragment.participants);
+        let top = self.next_top();
+
+        // Cover the lifelines of all the involved participants. Prefer extending the frame to the
+        // right in case it is stretched by its labels or content.
+        let render_ctx = FragmentRenderCtx {
+            dividers: Vec::new(),
+            glyphs: self.glyphs(),
+        };
+        let vars = Vars::new();
+        let first_participant = &self.participants[first];
+        let last_participant = &self.participants[last];
+        let mut constraints = vec![
+            vars.top | EQ(REQUIRED) | top,
+            vars.left | GE(REQUIRED) | 0.0,
+            vars.width
+                | GE(REQUIRED)
+                | fragment.width(&render_ctx).expect("Fragment missing width") as f64,
+            (vars.left + padding) | LE(REQUIRED) | first_participant.vars.center(),
+            (vars.left + padding) | EQ(STRONG) | first_participant.vars.center(),
+            vars.right() | GE(REQUIRED) | (last_participant.vars.center() + padding + 1.0),
+            vars.right() | EQ(MEDIUM) | (last_participant.vars.center() + padding + 1.0),
+        ];
+        constraints.extend(self.enclose(&vars));
+
+        // Keep the lifelines of the participants that are not involved outside of the frame
+        if first > 0 {
+            let previous = &mut self.participants[first - 1];
+            previous
+                .constraints
+                .push(vars.left | GE(REQUIRED) | (previous.vars.center() + spacing));
+        }
+        if let Some(next) = self.participants.get_mut(last + 1) {
+            next.constraints
+                .push(next.vars.center() | GE(REQUIRED) | (vars.right() + spacing));
+        }
+
+        // Leave space for the header
+        self.advance(vars.top + 1.0);
+        self.open_fragments.push(self.fragments.len());
+        self.fragments.push(Node {
+            vars,
+            constraints,
+            data: fragment,
+            _phantom: PhantomData,
+        });
+    }
+
+    /// Separates the subsequent elements of the innermost open fragment into a new section
+    pub fn add_fragment_section(&mut self) {
+        let index = *self.open_fragments.last().expect("No open fragment");
+        let top = self.cursor.clone().expect("Fragment missing header");
+
+        let divider = Variable::new();
+        let node = &mut self.fragments[index];
+        node.constraints.push(divider | EQ(REQUIRED) | top);
+        node.data.dividers.push(divider);
+
+        self.advance(divider + 1.0);
+    }
+
+    /// Closes the innermost open fragment
+    pub fn end_fragment(&mut self) {
+        let index = self.open_fragments.pop().expect("No open fragment");
+        let bottom = self.cursor.clone().expect("Fragment missing header");
+
+        // Leave space for the footer
+        let node = &mut self.fragments[index];
+        let footer = node.vars.bottom();
+        node.constraints
+            .push(footer.clone() | EQ(REQUIRED) | (bottom + 1.0));
+
+        self.advance(footer);
+    }
+
+    /// The render context of the message, describing which participants it connects
+    fn message_ctx(&self, message: &Message) -> MessageRenderCtx {
+        MessageRenderCtx {
+            source_idx: self.endpoint_index(&message.source),
+            target_idx: self.endpoint_index(&message.target),
+            right_border_idx: self.participants.len() + 1,
+            max_label_width: self.options.max_label_width,
+            glyphs: self.glyphs(),
+        }
+    }
+
+    /// The glyphs the text diagram is drawn with
+    fn glyphs(&self) -> &'static Glyphs {
+        Glyphs::of(self.options.charset)
+    }
+
+    fn bare_ctx(&self) -> BareRenderCtx {
+        BareRenderCtx {
+            glyphs: self.glyphs(),
+        }
+    }
+
+    /// Solves the constraints of all the elements and computes the size of the diagram
+    fn solve(&mut self) -> Result<Extent, LayoutError> {
+        // End the activations that are still open at the bottom of the diagram
+        while let Some(&(participant, _)) = self.open_activations.last() {
+            let bottom = self
+                .cursor
+                .clone()
+                .map(|cursor| cursor - 1.0)
+                .unwrap_or_else(|| Expression::from(self.lifeline_top));
+            self.close_activation(participant, bottom);
+        }
+
+        // Pull the lifelines up right below the tallest participant
+        self.solver
+            .add_constraint(self.lifeline_top | EQ(WEAK) | 0.0)
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+
+        // Keep the right border of the diagram to the right of all the participants
+        let right_border = self
+            .participants
+            .iter()
+            .map(|node| self.right_border | GE(REQUIRED) | (node.vars.right() + 1.0))
+            .chain(Some(self.right_border | EQ(WEAK) | 0.0))
+            .collect::<Vec<_>>();
+        self.solver
+            .add_constraints(&right_border)
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+
+        // Make room for the header and the title above the participants, and for the top borders
+        // of the boxes
+        let heading_height = self.heading_height();
+        let frame_height = self.frame_height();
+        let heading = self
+            .participants
+            .iter()
+            .map(|node| node.vars.top | GE(REQUIRED) | (heading_height + frame_height) as f64)
+            .chain(
+                self.boxes
+                    .iter()
+                    .map(|node| node.vars.top | EQ(REQUIRED) | heading_height as f64),
+            )
+            .collect::<Vec<_>>();
+        self.solver
+            .add_constraints(&heading)
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+
+        // Align the bottom edges of the participants that exist from the start, the ones created
+        // by a message are placed next to it instead
+        let head_row = self
+            .participants
+            .iter()
+            .zip(&self.lifespans)
+            .filter(|(_, lifespan)| !lifespan.created)
+            .map(|(node, _)| node.vars.bottom() | EQ(REQUIRED) | self.lifeline_top)
+            .collect::<Vec<_>>();
+        self.solver
+            .add_constraints(&head_row)
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+
+        // The solver picks an arbitrary solution when several of them are equally optimal. Break
+        // the ties by packing every element to the left and keeping it as narrow as possible, so
+        // that the same input always results in the same layout.
+        let tie_breakers = self
+            .participants
+            .iter()
+            .map(|node| &node.vars)
+            .chain(self.messages.iter().map(|node| &node.vars))
+            .chain(self.notes.iter().map(|node| &node.vars))
+            .chain(self.references.iter().map(|node| &node.vars))
+            .chain(self.fragments.iter().map(|node| &node.vars))
+            .chain(self.dividers.iter().map(|node| &node.vars))
+            .chain(self.delays.iter().map(|node| &node.vars))
+            .chain(self.boxes.iter().map(|node| &node.vars))
+            .flat_map(|vars| vec![vars.left | EQ(WEAK) | 0.0, vars.width | EQ(WEAK) | 0.0])
+            .collect::<Vec<_>>();
+        self.solver
+            .add_constraints(&tie_breakers)
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.participants
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.messages
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.notes
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.references
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.fragments
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.activations
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.dividers
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.delays
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+        self.solver
+            .add_constraints(
+                self.boxes
+                    .iter()
+                    .flat_map(|node| &node.constraints)
+                    .collect::<Vec<&Constraint>>(),
+            )
+            .map_err(|_| LayoutError::UnsatisfiableConstraints)?;
+
+        // Compute the required canvas size. The participants are mirrored at the bottom.
+        let max_right = self
+            .participants
+            .iter()
+            .map(|p| p.coords(&self.solver).right())
+            .chain(self.messages.iter().map(|p| p.coords(&self.solver).right()))
+            .chain(self.notes.iter().map(|p| p.coords(&self.solver).right()))
+            .chain(
+                self.references
+                    .iter()
+                    .map(|p| p.coords(&self.solver).right()),
+            )
+            .chain(
+                self.fragments
+                    .iter()
+                    .map(|p| p.coords(&self.solver).right()),
+            )
+            .chain(self.dividers.iter().map(|p| p.coords(&self.solver).right()))
+            .chain(self.delays.iter().map(|p| p.coords(&self.solver).right()))
+            .chain(self.boxes.iter().map(|p| p.coords(&self.solver).right()))
+            .max()
+            .map(|right| right + 1)
+            .unwrap_or(0);
+        let caption_width = self
+            .heading()
+            .into_iter()
+            .map(|(text, _)| text)
+            .chain(self.footer.as_deref())
+            .map(text_width)
+            .max()
+            .unwrap_or(0);
+        let body_bottom = self
+            .participants
+            .iter()
+            .map(|p| p.coords(&self.solver).bottom())
+            .chain(
+                self.messages
+                    .iter()
+                    .map(|p| p.coords(&self.solver).bottom()),
+            )
+            .chain(self.notes.iter().map(|p| p.coords(&self.solver).bottom()))
+            .chain(
+                self.references
+                    .iter()
+                    .map(|p| p.coords(&self.solver).bottom()),
+            )
+            .chain(
+                self.fragments
+                    .iter()
+                    .map(|p| p.coords(&self.solver).bottom()),
+            )
+            .chain(
+                self.dividers
+                    .iter()
+                    .map(|p| p.coords(&self.solver).bottom()),
+            )
+            .chain(self.delays.iter().map(|p| p.coords(&self.solver).bottom()))
+            // Leave room for the crosses ending the lifelines of the destroyed participants
+            .chain(
+                (0..self.participants.len())
+                    .filter_map(|index| self.destroyed_at(index).map(|row| row + 1)),
+            )
+            .max()
+            .unwrap_or(0);
+        let foot_top = body_bottom.max(heading_height);
+        // The destroyed participants are not mirrored
+        let foot_height = match self.options.hide_footbox {
+            true => 0,
+            false => self
+                .participants
+                .iter()
+                .zip(&self.lifespans)
+                .filter(|(_, lifespan)| lifespan.destroyed.is_none())
+                .map(|(node, _)| node.coords(&self.solver).height)
+                .max()
+                .unwrap_or(0),
+        };
+        let foot_bottom = foot_top + foot_height;
+        let max_bottom = foot_bottom + frame_height + self.footing_height();
+
+        Ok(Extent {
+            width: max_right.max(caption_width),
+            height: max_bottom,
+            foot_top,
+            foot_bottom,
+        })
+    }
+
+    /// Renders the layout as text and consumes itself.
+    pub fn render(mut self) -> Result<String, LayoutError> {
+        let extent = self.solve()?;
+        let Extent {
+            width: max_right,
+            height: max_bottom,
+            foot_top,
+            foot_bottom,
+        } = extent;
+
+        // Render the layout
+        let bare_ctx = self.bare_ctx();
+
+        // Setup the canvas
+        let mut canvas = TextCanvas::new(max_right, max_bottom);
+
+        // Draw the header, the title and the footer
+        for caption in self.captions(&extent) {
+            canvas.draw(
+                0,
+                caption.row,
+                &[centered(caption.text, max_right).as_str()],
+            )?;
+        }
+
+        // Draw the boxes around the groups of participants, spanning the mirrored participants
+        for node in &self.boxes {
+            let coords = node.coords(&self.solver);
+            node.render(
+                &mut canvas.region(
+                    coords.left,
+                    coords.top,
+                    coords.width,
+                    foot_bottom + 1 - coords.top,
+                ),
+                &bare_ctx,
+            )?;
+        }
 
         // Draw the participants and their lifelines
-        let lifeline = vec!["│"; max_bottom];
-        for node in &self.participants {
+        let vertical = bare_ctx.glyphs.vertical.to_string();
+        for (index, node) in self.participants.iter().enumerate() {
             let coords = node.coords(&self.solver);
-            canvas
-                .draw(coords.center(), 0, &lifeline)
-                .expect("Draw failed");
+            let rows = self.lifeline_rows(index, &extent);
+            if !rows.is_empty() {
+                canvas.draw(
+                    coords.center(),
+                    rows.start,
+                    &vec![vertical.as_str(); rows.len()],
+                )?;
+            }
 
             node.render(
                 &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
                 &bare_ctx,
-            )
-            .expect("Draw failed");
+            )?;
+            if !self.options.hide_footbox && self.destroyed_at(index).is_none() {
+                node.render(
+                    &mut canvas.region(coords.left, foot_top, coords.width, coords.height),
+                    &bare_ctx,
+                )?;
+            }
+        }
+
+        // Draw the activation bars on top of the lifelines
+        for node in &self.activations {
+            let coords = match self.activation_coords(node, &extent) {
+                Some(coords) => coords,
+                None => continue,
+            };
+            node.render(
+                &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
+                &bare_ctx,
+            )?;
+        }
+
+        // Cross out the ends of the lifelines of the destroyed participants
+        let cross = bare_ctx.glyphs.cross.to_string();
+        for (index, node) in self.participants.iter().enumerate() {
+            if let Some(row) = self.destroyed_at(index) {
+                let center = node.coords(&self.solver).center();
+                canvas.draw(center, row, &[cross.as_str()])?;
+            }
+        }
+
+        // Break the lifelines during the delays
+        let dotted = bare_ctx.glyphs.dotted.to_string();
+        for node in &self.delays {
+            let coords = node.coords(&self.solver);
+            for (index, participant) in self.participants.iter().enumerate() {
+                let rows = self.lifeline_rows(index, &extent);
+                let (start, end) = (coords.top.max(rows.start), coords.bottom().min(rows.end));
+                if start < end {
+                    canvas.draw(
+                        participant.coords(&self.solver).center(),
+                        start,
+                        &vec![dotted.as_str(); end - start],
+                    )?;
+                }
+            }
             node.render(
-                &mut canvas.region(coords.left, max_bottom - 3, coords.width, coords.height),
+                &mut canvas.region(0, coords.top, max_right, coords.height),
                 &bare_ctx,
-            )
-            .expect("Draw failed");
+            )?;
         }
 
-        // Draw the messages
-        for node in &self.messages {
+        // Draw the fragments
+        for node in &self.fragments {
             let coords = node.coords(&self.solver);
-            let source_idx = self
-                .participants
-                .iter()
-                .position(|p| p.data == node.data.source)
-                .unwrap();
-            let target_idx = self
-                .participants
+            let dividers = node
+                .data
+                .dividers
                 .iter()
-                .position(|p| p.data == node.data.target)
-                .unwrap();
+                .map(|divider| self.solver.get_value(*divider) as usize - coords.top)
+                .collect();
             node.render(
                 &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
-                &MessageRenderCtx {
-                    source_idx,
-                    target_idx,
+                &FragmentRenderCtx {
+                    dividers,
+                    glyphs: bare_ctx.glyphs,
                 },
-            )
-            .expect("Draw failed");
+            )?;
+        }
+
+        // Draw the dividers across the whole diagram, or across the fragment enclosing them
+        for node in &self.dividers {
+            let coords = node.coords(&self.solver);
+            let (left, width) = self.divider_span(&coords, &extent);
+            node.render(
+                &mut canvas.region(left, coords.top, width, coords.height),
+                &bare_ctx,
+            )?;
+        }
+
+        // Draw the reference frames over the lifelines
+        for node in &self.references {
+            let coords = node.coords(&self.solver);
+            node.render(
+                &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
+                &bare_ctx,
+            )?;
+        }
+
+        // Draw the notes
+        for node in &self.notes {
+            let coords = node.coords(&self.solver);
+            node.render(
+                &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
+                &bare_ctx,
+            )?;
+        }
+
+        // Draw the messages
+        for node in &self.messages {
+            let coords = node.coords(&self.solver);
+            node.render(
+                &mut canvas.region(coords.left, coords.top, coords.width, coords.height),
+                &self.message_ctx(&node.data),
+            )?;
         }
-        canvas.content()
+        Ok(canvas.content())
+    }
+
+    /// Renders the layout as an SVG document and consumes itself.
+    pub fn render_svg(mut self) -> Result<String, LayoutError> {
+        let extent = self.solve()?;
+        Ok(svg::render(&self, &extent))
+    }
+
+    /// Solves the layout and exports the positions and sizes of its elements.
+    pub fn geometry(mut self) -> Result<Geometry, LayoutError> {
+        let extent = self.solve()?;
+        Ok(json::export(&self, &extent))
     }
 }
 
@@ -443,36 +2352,59 @@ mod test {
 
     #[test]
     fn smoke() {
-        let mut layout = Layout::new();
+        let mut layout = Layout::new(TransformOptions::default());
         let participant_alice = Arc::from(Participant {
             id: "alice".into(),
             name: "Alice".into(),
+            kind: ParticipantKind::Actor,
         });
         let participant_bob = Arc::from(Participant {
             id: "bob".into(),
             name: "Bob".into(),
+            kind: ParticipantKind::Participant,
         });
         layout.add_participant(participant_alice.clone());
         layout.add_participant(participant_bob.clone());
         layout.add_message(Message {
-            source: participant_alice.clone(),
-            target: participant_bob.clone(),
+            source: Endpoint::Participant(participant_alice.clone()),
+            target: Endpoint::Participant(participant_bob.clone()),
             payload: "hello".to_string(),
             edge_style: EdgeStyle::Continuous,
+            head: ArrowHead::Filled,
+            bidirectional: false,
+            activation: None,
+            create: false,
         });
         layout.add_message(Message {
-            source: participant_bob.clone(),
-            target: participant_alice.clone(),
+            source: Endpoint::Participant(participant_bob.clone()),
+            target: Endpoint::Participant(participant_alice.clone()),
             payload: "hello back".to_string(),
             edge_style: EdgeStyle::Continuous,
+            head: ArrowHead::Filled,
+            bidirectional: false,
+            activation: None,
+            create: false,
         });
         layout.add_message(Message {
-            source: participant_bob.clone(),
-            target: participant_bob.clone(),
+            source: Endpoint::Participant(participant_bob.clone()),
+            target: Endpoint::Participant(participant_bob.clone()),
             payload: "who am i?".to_string(),
             edge_style: EdgeStyle::Dashed,
+            head: ArrowHead::Filled,
+            bidirectional: false,
+            activation: None,
+            create: false,
         });
-        let output = layout.render();
-        assert!(output.len() > 0);
+        let output = layout.render().unwrap();
+        assert!(!output.is_empty());
+    }
+
+    #[test]
+    fn wrap_text() {
+        assert_eq!(wrap("lorem ipsum dolor", 11), vec!["lorem ipsum", "dolor"]);
+        assert_eq!(wrap("lorem\nipsum dolor", 20), vec!["lorem", "ipsum dolor"]);
+        assert_eq!(wrap("abcdefgh ij", 3), vec!["abc", "def", "gh", "ij"]);
+        assert_eq!(wrap("", 3), vec![""]);
+        assert_eq!(wrap("日本語", 4), vec!["日本", "語"]);
     }
 }
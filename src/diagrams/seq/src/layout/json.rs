@@ -0,0 +1,111 @@
+/// Structured export of a solved layout
+///
+/// The coordinates are the ones of the text rendering, i.e. columns and rows of the grid the
+/// diagram is drawn on.
+use super::{Endpoint, Extent, Layout, Vars};
+use serde::Serialize;
+
+/// The area occupied by an element
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Bounds {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl From<&Vars<usize>> for Bounds {
+    fn from(coords: &Vars<usize>) -> Self {
+        Self {
+            left: coords.left,
+            top: coords.top,
+            width: coords.width,
+            height: coords.height,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParticipantGeometry {
+    pub id: String,
+    pub name: String,
+    /// The box drawn at the top of the lifeline
+    #[serde(flatten)]
+    pub bounds: Bounds,
+    /// The top row of the box mirrored below the lifeline, unless it is not drawn
+    pub foot_top: Option<usize>,
+}
+
+/// One end of a message, serialized the same way as in the parsed diagram
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageEnd {
+    /// The ID of a participant
+    Participant(String),
+    /// The left edge of the diagram, for the messages coming from or going to the outside
+    LeftBorder,
+    /// The right edge of the diagram, for the messages coming from or going to the outside
+    RightBorder,
+}
+
+impl From<&Endpoint> for MessageEnd {
+    fn from(endpoint: &Endpoint) -> Self {
+        match endpoint {
+            Endpoint::Participant(participant) => MessageEnd::Participant(participant.id.clone()),
+            Endpoint::LeftBorder => MessageEnd::LeftBorder,
+            Endpoint::RightBorder => MessageEnd::RightBorder,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageGeometry {
+    pub source: MessageEnd,
+    pub target: MessageEnd,
+    pub payload: String,
+    /// The area of the arrow along with its label
+    #[serde(flatten)]
+    pub bounds: Bounds,
+}
+
+/// The positions and sizes of the participants and the messages of a solved layout
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Geometry {
+    pub width: usize,
+    pub height: usize,
+    pub participants: Vec<ParticipantGeometry>,
+    pub messages: Vec<MessageGeometry>,
+}
+
+pub(super) fn export(layout: &Layout, extent: &Extent) -> Geometry {
+    let participants = layout
+        .participants
+        .iter()
+        .enumerate()
+        .map(|(index, node)| ParticipantGeometry {
+            id: node.data.id.clone(),
+            name: node.data.name.clone(),
+            bounds: Bounds::from(&node.coords(&layout.solver)),
+            foot_top: match layout.options.hide_footbox || layout.destroyed_at(index).is_some() {
+                true => None,
+                false => Some(extent.foot_top),
+            },
+        })
+        .collect();
+    let messages = layout
+        .messages
+        .iter()
+        .map(|node| MessageGeometry {
+            source: MessageEnd::from(&node.data.source),
+            target: MessageEnd::from(&node.data.target),
+            payload: node.data.payload.clone(),
+            bounds: Bounds::from(&node.coords(&layout.solver)),
+        })
+        .collect();
+    Geometry {
+        width: extent.width,
+        height: extent.height,
+        participants,
+        messages,
+    }
+}
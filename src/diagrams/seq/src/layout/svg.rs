@@ -0,0 +1,661 @@
+/// SVG rendering of a solved layout
+///
+/// The drawing uses the same grid as the text rendering, with every cell scaled to a fixed number
+/// of pixels, so that both outputs share the geometry computed by the constraint solver.
+use super::{
+    text_lines, text_width, ArrowHead, CaptionKind, Delay, Divider, EdgeStyle, Extent, Fragment,
+    Layout, Message, MessageRenderCtx, Note, Participant, ParticipantBox, ParticipantKind,
+    Reference, Vars, REFERENCE_TAG,
+};
+
+/// The width of a grid cell in pixels
+const CELL_WIDTH: f64 = 8.0;
+/// The height of a grid cell in pixels
+const CELL_HEIGHT: f64 = 16.0;
+
+const STYLE: &str = "\
+text { font-family: monospace; font-size: 13px; dominant-baseline: central; }
+.background { fill: #fff; }
+.shape { fill: #fff; stroke: #000; }
+.note { fill: #ffc; stroke: #000; }
+.group { fill: #f4f4f4; stroke: #888; }
+.activation { fill: #ddd; stroke: #000; }
+.line { fill: none; stroke: #000; }
+.lifeline { stroke: #888; stroke-dasharray: 4 4; }
+.dashed { stroke-dasharray: 4 3; }
+.dotted { stroke-dasharray: 1 3; }
+.label { font-weight: bold; }
+.title { font-weight: bold; }
+.caption { fill: #555; }
+#arrowhead { fill: #000; }";
+
+/// The horizontal center of the column in pixels
+fn x(column: usize) -> f64 {
+    (column as f64 + 0.5) * CELL_WIDTH
+}
+
+/// The vertical center of the row in pixels
+fn y(row: usize) -> f64 {
+    (row as f64 + 0.5) * CELL_HEIGHT
+}
+
+/// The left edge of the column in pixels
+fn left_edge(column: usize) -> f64 {
+    column as f64 * CELL_WIDTH
+}
+
+/// The top edge of the row in pixels
+fn top_edge(row: usize) -> f64 {
+    row as f64 * CELL_HEIGHT
+}
+
+/// Escapes the characters that have a special meaning in XML
+fn escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+enum Anchor {
+    Start,
+    Middle,
+}
+
+/// A flat list of SVG elements
+struct Document {
+    elements: Vec<String>,
+}
+
+impl Document {
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, class: &str) {
+        self.elements.push(format!(
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" class="{}"/>"#,
+            x1, y1, x2, y2, class
+        ));
+    }
+
+    fn rect(&mut self, x: f64, y: f64, width: f64, height: f64, class: &str) {
+        self.elements.push(format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" class="{}"/>"#,
+            x, y, width, height, class
+        ));
+    }
+
+    fn circle(&mut self, cx: f64, cy: f64, r: f64, class: &str) {
+        self.elements.push(format!(
+            r#"<circle cx="{}" cy="{}" r="{}" class="{}"/>"#,
+            cx, cy, r, class
+        ));
+    }
+
+    fn ellipse(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, class: &str) {
+        self.elements.push(format!(
+            r#"<ellipse cx="{}" cy="{}" rx="{}" ry="{}" class="{}"/>"#,
+            cx, cy, rx, ry, class
+        ));
+    }
+
+    fn path(&mut self, d: &str, class: &str) {
+        self.elements
+            .push(format!(r#"<path d="{}" class="{}"/>"#, d, class));
+    }
+
+    /// Draws the path with the arrowhead at its end, and at its start as well if bidirectional
+    fn arrow(&mut self, d: &str, class: &str, head: &ArrowHead, bidirectional: bool) {
+        let marker = match head {
+            ArrowHead::Filled => "arrowhead",
+            ArrowHead::Open => "open-arrowhead",
+            ArrowHead::Cross => "cross",
+        };
+        let start = match bidirectional {
+            true => format!(r#" marker-start="url(#{})""#, marker),
+            false => String::new(),
+        };
+        self.elements.push(format!(
+            r#"<path d="{}" class="{}"{} marker-end="url(#{})"/>"#,
+            d, class, start, marker
+        ));
+    }
+
+    fn text(&mut self, x: f64, y: f64, text: &str, anchor: Anchor, class: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let anchor = match anchor {
+            Anchor::Start => "start",
+            Anchor::Middle => "middle",
+        };
+        let class = match class.is_empty() {
+            true => String::new(),
+            false => format!(r#" class="{}""#, class),
+        };
+        self.elements.push(format!(
+            r#"<text x="{}" y="{}" text-anchor="{}"{} xml:space="preserve">{}</text>"#,
+            x,
+            y,
+            anchor,
+            class,
+            escape(text)
+        ));
+    }
+
+    /// Draws the lines of the text centered around the given column, starting at the given row
+    fn centered_lines(&mut self, center: f64, top: usize, text: &[&str]) {
+        for (row, line) in text.iter().enumerate() {
+            self.text(center, y(top + row), line, Anchor::Middle, "");
+        }
+    }
+
+    /// Draws a horizontal line with the label embedded in it, the way the frames are drawn in the
+    /// text rendering
+    fn labelled_line(&mut self, left: usize, right: usize, row: usize, label: &str, class: &str) {
+        if label.is_empty() {
+            self.line(x(left), y(row), x(right), y(row), class);
+            return;
+        }
+        let label_len = text_width(label);
+        self.line(x(left), y(row), left_edge(left + 2), y(row), class);
+        self.text(left_edge(left + 3), y(row), label, Anchor::Start, "label");
+        self.line(
+            left_edge(left + 4 + label_len),
+            y(row),
+            x(right),
+            y(row),
+            class,
+        );
+    }
+
+    fn finish(self, width: usize, height: usize) -> String {
+        let (width, height) = (left_edge(width), top_edge(height));
+        let mut output = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+            width, height
+        );
+        output.push('\n');
+        output.push_str(&format!("<defs>\n<style>\n{}\n</style>\n", STYLE));
+        output.push_str(concat!(
+            r#"<marker id="arrowhead" viewBox="0 0 10 10" refX="10" refY="5" "#,
+            r#"markerWidth="8" markerHeight="8" orient="auto-start-reverse">"#,
+            r#"<path d="M 0 0 L 10 5 L 0 10 z"/></marker>"#,
+            "\n",
+            r#"<marker id="open-arrowhead" viewBox="0 0 10 10" refX="10" refY="5" "#,
+            r#"markerWidth="8" markerHeight="8" orient="auto-start-reverse">"#,
+            r#"<path d="M 0 0 L 10 5 L 0 10" class="line"/></marker>"#,
+            "\n",
+            r#"<marker id="cross" viewBox="0 0 10 10" refX="5" refY="5" "#,
+            r#"markerWidth="8" markerHeight="8">"#,
+            r#"<path d="M 0 0 L 10 10 M 10 0 L 0 10" class="line"/></marker>"#,
+            "\n</defs>\n"
+        ));
+        output.push_str(r#"<rect width="100%" height="100%" class="background"/>"#);
+        output.push('\n');
+        for element in self.elements {
+            output.push_str(&element);
+            output.push('\n');
+        }
+        output.push_str("</svg>");
+        output
+    }
+}
+
+fn participant(doc: &mut Document, participant: &Participant, coords: &Vars<usize>) {
+    let lines = text_lines(&participant.name);
+    // Keep the shapes symmetric around the lifeline
+    let center = x(coords.center());
+    let half_width = (coords.width as f64 / 2.0 - 0.5) * CELL_WIDTH;
+    let (x0, x1) = (center - half_width, center + half_width);
+    let (y0, y1) = (y(coords.top), y(coords.bottom() - 1));
+    match participant.kind {
+        ParticipantKind::Participant => {
+            doc.rect(x0, y0, x1 - x0, y1 - y0, "shape");
+            doc.centered_lines(center, coords.top + 1, &lines);
+        }
+        ParticipantKind::Queue => {
+            let separator = x1 - CELL_WIDTH;
+            doc.rect(x0, y0, x1 - x0, y1 - y0, "shape");
+            doc.line(separator, y0, separator, y1, "line");
+            doc.centered_lines((x0 + separator) / 2.0, coords.top + 1, &lines);
+        }
+        ParticipantKind::Database => {
+            // The lid spans the first two rows
+            let (rx, ry) = ((x1 - x0) / 2.0, CELL_HEIGHT / 2.0);
+            let lid = y0 + ry;
+            doc.path(
+                &format!(
+                    "M {} {} L {} {} A {} {} 0 0 0 {} {} L {} {} Z",
+                    x0,
+                    lid,
+                    x0,
+                    y1 - ry,
+                    rx,
+                    ry,
+                    x1,
+                    y1 - ry,
+                    x1,
+                    lid
+                ),
+                "shape",
+            );
+            doc.ellipse(center, lid, rx, ry, "shape");
+            doc.centered_lines(center, coords.top + 2, &lines);
+        }
+        ParticipantKind::Actor => {
+            let head = 5.0;
+            let shoulders = y(coords.top) + head;
+            let hips = y(coords.top + 2) - CELL_HEIGHT / 4.0;
+            let feet = y(coords.top + 2) + CELL_HEIGHT / 2.0;
+            doc.circle(center, y(coords.top), head, "shape");
+            doc.line(center, shoulders, center, hips, "line");
+            doc.line(
+                center - CELL_WIDTH,
+                y(coords.top + 1),
+                center + CELL_WIDTH,
+                y(coords.top + 1),
+                "line",
+            );
+            doc.line(center, hips, center - CELL_WIDTH, feet, "line");
+            doc.line(center, hips, center + CELL_WIDTH, feet, "line");
+            doc.centered_lines(center, coords.top + 3, &lines);
+        }
+        ParticipantKind::Boundary => {
+            let radius = 2.5 * CELL_WIDTH;
+            let bar = x(coords.center() - 4);
+            doc.line(bar, y(coords.top), bar, y(coords.top + 2), "line");
+            doc.line(
+                bar,
+                y(coords.top + 1),
+                center - radius,
+                y(coords.top + 1),
+                "line",
+            );
+            doc.circle(center, y(coords.top + 1), radius, "shape");
+            doc.centered_lines(center, coords.top + 3, &lines);
+        }
+    }
+}
+
+/// Draws the frame around a group of participants, with the label in its top row
+fn participant_box(
+    doc: &mut Document,
+    participant_box: &ParticipantBox,
+    coords: &Vars<usize>,
+    bottom: usize,
+) {
+    let (x0, x1) = (x(coords.left), x(coords.right() - 1));
+    doc.rect(
+        x0,
+        top_edge(coords.top),
+        x1 - x0,
+        y(bottom) - top_edge(coords.top),
+        "group",
+    );
+    doc.text(
+        (x0 + x1) / 2.0,
+        y(coords.top),
+        &participant_box.label(),
+        Anchor::Middle,
+        "label",
+    );
+}
+
+fn note(doc: &mut Document, note: &Note, coords: &Vars<usize>) {
+    let lines = text_lines(&note.text);
+    let (x0, x1) = (x(coords.left), x(coords.right() - 1));
+    let (y0, y1) = (y(coords.top), y(coords.top + lines.len() + 1));
+    doc.rect(x0, y0, x1 - x0, y1 - y0, "note");
+
+    // The lines are left-aligned within a block centered in the box
+    let left_padding = (coords.width - 2 - text_width(&note.text)) / 2;
+    for (row, line) in lines.iter().enumerate() {
+        doc.text(
+            left_edge(coords.left + 1 + left_padding),
+            y(coords.top + 1 + row),
+            line,
+            Anchor::Start,
+            "",
+        );
+    }
+}
+
+fn fragment(doc: &mut Document, fragment: &Fragment, coords: &Vars<usize>, dividers: &[usize]) {
+    let (left, right) = (coords.left, coords.right() - 1);
+    let (top, bottom) = (coords.top, coords.bottom() - 1);
+    let labels = fragment.labels();
+
+    doc.line(x(left), y(top), x(left), y(bottom), "line");
+    doc.line(x(right), y(top), x(right), y(bottom), "line");
+    doc.labelled_line(left, right, top, &labels[0], "line");
+    for (divider, label) in dividers.iter().zip(labels.iter().skip(1)) {
+        doc.labelled_line(left, right, *divider, label, "line dashed");
+    }
+    doc.line(x(left), y(bottom), x(right), y(bottom), "line");
+}
+
+/// Draws a frame hiding the lifelines it spans, with the tag in its top border
+fn reference(doc: &mut Document, reference: &Reference, coords: &Vars<usize>) {
+    let (left, right) = (coords.left, coords.right() - 1);
+    let (top, bottom) = (coords.top, coords.bottom() - 2);
+
+    doc.rect(
+        x(left),
+        y(top),
+        x(right) - x(left),
+        y(bottom) - y(top),
+        "background",
+    );
+    doc.line(x(left), y(top), x(left), y(bottom), "line");
+    doc.line(x(right), y(top), x(right), y(bottom), "line");
+    doc.labelled_line(left, right, top, REFERENCE_TAG, "line");
+    doc.line(x(left), y(bottom), x(right), y(bottom), "line");
+    doc.centered_lines(
+        (x(left) + x(right)) / 2.0,
+        top + 1,
+        &text_lines(&reference.label),
+    );
+}
+
+/// Draws a double line across the whole diagram with the label in the middle
+fn divider(doc: &mut Document, divider: &Divider, row: usize, (left, width): (usize, usize)) {
+    let label_len = match divider.label.is_empty() {
+        true => 0,
+        false => text_width(&divider.label) + 2,
+    };
+    let label_left = left + (width - label_len) / 2;
+    for offset in &[-1.5, 1.5] {
+        let row_y = y(row) + offset;
+        doc.line(left_edge(left), row_y, left_edge(label_left), row_y, "line");
+        doc.line(
+            left_edge(label_left + label_len),
+            row_y,
+            left_edge(left + width),
+            row_y,
+            "line",
+        );
+    }
+    doc.text(
+        left_edge(left) + left_edge(width) / 2.0,
+        y(row),
+        &divider.label,
+        Anchor::Middle,
+        "label",
+    );
+}
+
+/// Replaces the lifelines with dotted ones during the delay
+fn delay(
+    doc: &mut Document,
+    layout: &Layout,
+    delay: &Delay,
+    coords: &Vars<usize>,
+    extent: &Extent,
+) {
+    let width = extent.width;
+    doc.rect(
+        0.0,
+        top_edge(coords.top),
+        left_edge(width),
+        top_edge(coords.height),
+        "background",
+    );
+    for (index, node) in layout.participants.iter().enumerate() {
+        let rows = layout.lifeline_rows(index, extent);
+        let (start, end) = (coords.top.max(rows.start), coords.bottom().min(rows.end));
+        if start < end {
+            let center = x(node.coords(&layout.solver).center());
+            doc.line(
+                center,
+                top_edge(start),
+                center,
+                top_edge(end),
+                "line dotted",
+            );
+        }
+    }
+    doc.text(
+        left_edge(width) / 2.0,
+        y(coords.top + 1),
+        &delay.label,
+        Anchor::Middle,
+        "",
+    );
+}
+
+fn message(
+    doc: &mut Document,
+    layout: &Layout,
+    message: &Message,
+    coords: &Vars<usize>,
+    ctx: &MessageRenderCtx,
+) {
+    let class = match message.edge_style {
+        EdgeStyle::Continuous => "line",
+        EdgeStyle::Dashed => "line dashed",
+    };
+    let lines = message.lines(ctx);
+    let arrow_row = coords.top + message.arrow_row(ctx);
+
+    // The message is placed between the lifelines (or the activation bars) it connects. End the
+    // arrow at the edge of the bar if there is one, otherwise at the lifeline or the edge of the
+    // diagram. The box of a participant created by the message starts half a cell past the
+    // column next to the message.
+    let (left, right) = (coords.left.saturating_sub(1), coords.right());
+    let bar_offset = CELL_WIDTH / 2.0 - 1.0;
+    let creates_left = message.create && ctx.points_left();
+    let creates_right = message.create && !ctx.points_left();
+    let left_x = match (
+        ctx.starts_at_left_border(),
+        layout.is_activated(left, arrow_row),
+    ) {
+        (true, _) => 0.0,
+        _ if creates_left => left_edge(left + 1),
+        (false, true) => x(left) + bar_offset,
+        (false, false) => x(left),
+    };
+    let right_x = match (
+        ctx.ends_at_right_border(),
+        layout.is_activated(right, arrow_row),
+    ) {
+        (true, _) => left_edge(right),
+        _ if creates_right => left_edge(right + 1),
+        (false, true) => x(right) - bar_offset,
+        (false, false) => x(right),
+    };
+
+    if ctx.is_loop() {
+        let turn = x(coords.left + 1);
+        doc.arrow(
+            &format!(
+                "M {} {} H {} V {} H {}",
+                left_x,
+                y(coords.top),
+                turn,
+                y(arrow_row),
+                left_x
+            ),
+            class,
+            &message.head,
+            message.bidirectional,
+        );
+        for (row, line) in lines.iter().enumerate() {
+            doc.text(
+                left_edge(coords.left + 3),
+                y(coords.top + 1 + row),
+                line,
+                Anchor::Start,
+                "",
+            );
+        }
+        return;
+    }
+
+    let (start, end) = match ctx.points_left() {
+        true => (right_x, left_x),
+        false => (left_x, right_x),
+    };
+    doc.arrow(
+        &format!("M {} {} H {}", start, y(arrow_row), end),
+        class,
+        &message.head,
+        message.bidirectional,
+    );
+    let center = left_edge(coords.left) + coords.width as f64 * CELL_WIDTH / 2.0;
+    let lines = lines.iter().map(String::as_str).collect::<Vec<_>>();
+    doc.centered_lines(center, coords.top, &lines);
+}
+
+impl Layout {
+    /// Whether an activation bar covers the cell
+    fn is_activated(&self, column: usize, row: usize) -> bool {
+        self.activations.iter().any(|node| {
+            let coords = node.coords(&self.solver);
+            coords.left == column && coords.top <= row && row < coords.bottom()
+        })
+    }
+}
+
+/// Draws the solved layout as an SVG document
+pub(super) fn render(layout: &Layout, extent: &Extent) -> String {
+    let mut doc = Document {
+        elements: Vec::new(),
+    };
+
+    // Draw the header, the title and the footer
+    for caption in layout.captions(extent) {
+        let class = match caption.kind {
+            CaptionKind::Title => "title",
+            CaptionKind::Header | CaptionKind::Footer => "caption",
+        };
+        doc.text(
+            left_edge(extent.width) / 2.0,
+            y(caption.row),
+            caption.text,
+            Anchor::Middle,
+            class,
+        );
+    }
+
+    // Draw the boxes around the groups of participants below everything else
+    for node in &layout.boxes {
+        let coords = node.coords(&layout.solver);
+        participant_box(&mut doc, &node.data, &coords, extent.foot_bottom);
+    }
+
+    // Draw the participants and their lifelines. The lifelines of the destroyed participants end
+    // with a cross.
+    for (index, node) in layout.participants.iter().enumerate() {
+        let coords = node.coords(&layout.solver);
+        let center = x(coords.center());
+        let rows = layout.lifeline_rows(index, extent);
+        let destroyed_at = layout.destroyed_at(index);
+        let end = match destroyed_at {
+            Some(row) => y(row),
+            None => top_edge(rows.end),
+        };
+        if !rows.is_empty() {
+            doc.line(center, top_edge(rows.start), center, end, "line lifeline");
+        }
+        participant(&mut doc, &node.data, &coords);
+        if let Some(row) = destroyed_at {
+            let size = CELL_WIDTH / 2.0;
+            doc.path(
+                &format!(
+                    "M {} {} L {} {} M {} {} L {} {}",
+                    center - size,
+                    y(row) - size,
+                    center + size,
+                    y(row) + size,
+                    center + size,
+                    y(row) - size,
+                    center - size,
+                    y(row) + size
+                ),
+                "line",
+            );
+        } else if !layout.options.hide_footbox {
+            let foot = Vars {
+                top: extent.foot_top,
+                ..coords
+            };
+            participant(&mut doc, &node.data, &foot);
+        }
+    }
+
+    // Break the lifelines during the delays
+    for node in &layout.delays {
+        let coords = node.coords(&layout.solver);
+        delay(&mut doc, layout, &node.data, &coords, extent);
+    }
+
+    // Draw the activation bars on top of the lifelines
+    for node in &layout.activations {
+        let coords = match layout.activation_coords(node, extent) {
+            Some(coords) => coords,
+            None => continue,
+        };
+        doc.rect(
+            left_edge(coords.left) + 1.0,
+            top_edge(coords.top),
+            CELL_WIDTH - 2.0,
+            top_edge(coords.height),
+            "activation",
+        );
+    }
+
+    // Draw the fragments
+    for node in &layout.fragments {
+        let coords = node.coords(&layout.solver);
+        let dividers = node
+            .data
+            .dividers
+            .iter()
+            .map(|divider| layout.solver.get_value(*divider) as usize)
+            .collect::<Vec<_>>();
+        fragment(&mut doc, &node.data, &coords, &dividers);
+    }
+
+    // Draw the dividers across the whole diagram
+    for node in &layout.dividers {
+        let coords = node.coords(&layout.solver);
+        let span = layout.divider_span(&coords, extent);
+        divider(&mut doc, &node.data, coords.top, span);
+    }
+
+    // Draw the reference frames over the lifelines
+    for node in &layout.references {
+        reference(&mut doc, &node.data, &node.coords(&layout.solver));
+    }
+
+    // Draw the notes
+    for node in &layout.notes {
+        note(&mut doc, &node.data, &node.coords(&layout.solver));
+    }
+
+    // Draw the messages
+    for node in &layout.messages {
+        let coords = node.coords(&layout.solver);
+        let ctx = layout.message_ctx(&node.data);
+        message(&mut doc, layout, &node.data, &coords, &ctx);
+    }
+
+    doc.finish(extent.width, extent.height)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escape_text() {
+        assert_eq!(
+            escape("a < b && \"c\""),
+            "a &lt; b &amp;&amp; &quot;c&quot;"
+        );
+    }
+}
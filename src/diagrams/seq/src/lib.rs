@@ -2,28 +2,197 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
-use diagram_base::TransformError;
+use diagram_base::{Diagnostic, IncludeResolver, NoIncludes, TransformError, TransformOptions};
 
+mod analysis;
+mod formatter;
 mod layout;
+mod linter;
 mod parser;
 mod renderer;
 
+pub use analysis::{Analysis, Occurrence, Participant};
+pub use layout::{Bounds, Geometry, MessageEnd, MessageGeometry, ParticipantGeometry};
+pub use parser::{
+    Activation, Alias, ArrowHead, Autonumber, EdgeStyle, Endpoint, Event, Fragment, FragmentKind,
+    FragmentSection, Message, Note, NotePlacement, ParticipantBox, ParticipantKind, Reference,
+    SequenceDiagram,
+};
+
+fn parser_diagnostic(err: parser::ParserError) -> Diagnostic {
+    match err {
+        parser::ParserError::SyntaxError(message, span) => {
+            Diagnostic::error("E0001", &message).with_span(span)
+        }
+        parser::ParserError::InvalidEscape(sequence, span) => Diagnostic::error(
+            "E0002",
+            &format!("invalid escape sequence `{}`", sequence),
+        )
+        .with_span(span)
+        .with_help(
+            "supported escape sequences are \\\", \\\\, \\/, \\n, \\t and \\uXXXX, the other control characters cannot be drawn",
+        ),
+        parser::ParserError::UnexpectedElse(kind, span) => Diagnostic::error(
+            "E0003",
+            &format!(
+                "{} fragments cannot have else branches",
+                format!("{:?}", kind).to_lowercase()
+            ),
+        )
+        .with_span(span)
+        .with_help("only alt and par fragments can have else branches"),
+        parser::ParserError::InvalidNumberFormat(format, span) => Diagnostic::error(
+            "E0004",
+            &format!("message number format `{}` has no placeholder", format),
+        )
+        .with_span(span)
+        .with_help("use {} to mark where the number goes, e.g. \"[{}]\""),
+        parser::ParserError::IncludeFailed(path, reason, span) => {
+            Diagnostic::error("E0005", &format!("cannot include `{}`: {}", path, reason))
+                .with_span(span)
+        }
+        parser::ParserError::IncludeCycle(path, span) => {
+            Diagnostic::error("E0006", &format!("`{}` includes itself", path))
+                .with_span(span)
+                .with_help("remove one of the includes of the cycle")
+        }
+        parser::ParserError::UsedAfterDestroy(id, span) => Diagnostic::error(
+            "E0007",
+            &format!("participant `{}` is used after it is destroyed", id),
+        )
+        .with_span(span)
+        .with_help("destroy the participant after its last message"),
+        parser::ParserError::CreatedAfterUse(id, span) => Diagnostic::error(
+            "E0008",
+            &format!("participant `{}` is created after it appears", id),
+        )
+        .with_span(span)
+        .with_help("create the participant with the first message it takes part in"),
+        parser::ParserError::NeverCreated(id, span) => Diagnostic::error(
+            "E0009",
+            &format!("participant `{}` is never created", id),
+        )
+        .with_span(span)
+        .with_help("send a message to the participant to create it, e.g. a->*b"),
+        parser::ParserError::InIncludedFile(path, err, span) => {
+            // Reported at the include site, the message locates the error in the included file
+            let inner = parser_diagnostic(*err);
+            let location = match inner.span {
+                Some(inner_span) => format!("{}:{}", path, inner_span.start),
+                None => path,
+            };
+            Diagnostic {
+                message: format!("{}: {}", location, inner.message),
+                span: Some(span),
+                ..inner
+            }
+        }
+    }
+}
+
 impl From<parser::ParserError> for TransformError {
     fn from(err: parser::ParserError) -> TransformError {
-        match err {
-            parser::ParserError::SyntaxError(details) => TransformError::ParseError(details),
-        }
+        TransformError::ParseError(Box::new(parser_diagnostic(err)))
+    }
+}
+
+impl From<renderer::RenderError> for TransformError {
+    fn from(err: renderer::RenderError) -> TransformError {
+        let diagnostic = match err {
+            renderer::RenderError::UnknownParticipant(id) => {
+                Diagnostic::error("E0100", &format!("unknown participant `{}`", id))
+            }
+            renderer::RenderError::Layout(layout::LayoutError::UnsatisfiableConstraints) => {
+                Diagnostic::error("E0101", "the diagram elements cannot be laid out together")
+            }
+            renderer::RenderError::Layout(layout::LayoutError::DrawFailed(_)) => Diagnostic::error(
+                "E0102",
+                "an element does not fit in the space laid out for it",
+            ),
+            renderer::RenderError::NumberOverflow(span) => {
+                Diagnostic::error("E0103", "the messages are numbered past the largest number")
+                    .with_span(span)
+                    .with_help("start the numbering at a smaller number or with a smaller step")
+            }
+        };
+        TransformError::RenderError(Box::new(diagnostic))
     }
 }
 
 pub fn transform(input: &str) -> Result<String, TransformError> {
-    let diagram = parser::diagram(input)?;
-    let output = renderer::render(diagram);
+    transform_with_options(input, &TransformOptions::default())
+}
+
+pub fn transform_with_options(
+    input: &str,
+    options: &TransformOptions,
+) -> Result<String, TransformError> {
+    transform_with_resolver(input, options, &NoIncludes)
+}
+
+/// Transforms the diagram, reading the files it includes with the given resolver
+pub fn transform_with_resolver(
+    input: &str,
+    options: &TransformOptions,
+    resolver: &dyn IncludeResolver,
+) -> Result<String, TransformError> {
+    let diagram = parser::diagram(input, resolver)?;
+    let output = renderer::render(diagram, options)?;
+    Ok(output)
+}
+
+/// Parses the diagram, reading the files it includes with the given resolver
+pub fn parse(
+    input: &str,
+    resolver: &dyn IncludeResolver,
+) -> Result<SequenceDiagram, TransformError> {
+    let diagram = parser::diagram(input, resolver)?;
+    Ok(diagram)
+}
+
+/// Lays out the diagram and computes the positions and sizes of its participants and messages
+pub fn geometry(
+    input: &str,
+    options: &TransformOptions,
+    resolver: &dyn IncludeResolver,
+) -> Result<Geometry, TransformError> {
+    let diagram = parser::diagram(input, resolver)?;
+    let geometry = renderer::geometry(diagram, options)?;
+    Ok(geometry)
+}
+
+/// Formats the diagram canonically, keeping its comments and includes
+pub fn format(input: &str) -> Result<String, TransformError> {
+    let output = formatter::format(input)?;
     Ok(output)
 }
 
+/// Finds the problems in the diagram that do not prevent it from being drawn
+pub fn lint(
+    input: &str,
+    resolver: &dyn IncludeResolver,
+) -> Result<Vec<Diagnostic>, TransformError> {
+    let diagnostics = linter::lint(input, resolver)?;
+    Ok(diagnostics)
+}
+
+/// Finds the participants of the diagram and where their IDs occur, e.g. for the editors
+pub fn analyze(input: &str, resolver: &dyn IncludeResolver) -> Result<Analysis, TransformError> {
+    let analysis = analysis::analyze(input, resolver)?;
+    Ok(analysis)
+}
+
+/// Tells whether the text can be used as a participant ID
+pub fn is_identifier(text: &str) -> bool {
+    analysis::is_identifier(text)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::MessageEnd;
+    use diagram_base::{NoIncludes, OutputFormat, TransformOptions};
+    use std::collections::HashMap;
+
     #[test]
     fn test_smoke() {
         let data = r#"
@@ -32,7 +201,91 @@ mod tests {
         a->b:"hey"
         "#;
         let result = super::transform(data).unwrap();
-        assert_eq!(result.contains("Foo"), true);
-        assert_eq!(result.contains("Bar"), true);
+        assert!(result.contains("Foo"));
+        assert!(result.contains("Bar"));
+    }
+
+    #[test]
+    fn test_error_diagnostic() {
+        let data = "opt {\n  a->b\n} else {\n  b->a\n}\n";
+        let err = super::transform(data).unwrap_err();
+        let diagnostic = err.diagnostic();
+        assert_eq!(diagnostic.code, "E0003");
+        assert_eq!(diagnostic.span.unwrap().start.line, 3);
+        assert!(diagnostic.help.is_some());
+    }
+
+    #[test]
+    fn test_number_overflow_diagnostic() {
+        let data = "a->b\nautonumber 18446744073709551615\na->b\nb->a\n";
+        let err = super::transform(data).unwrap_err();
+        let diagnostic = err.diagnostic();
+        assert_eq!(diagnostic.code, "E0103");
+        assert_eq!(diagnostic.span.unwrap().start.line, 2);
+    }
+
+    #[test]
+    fn test_included_error_diagnostic() {
+        let mut files = HashMap::new();
+        files.insert(
+            "common.diag".to_string(),
+            "a->b\nopt {\n} else {\n}\n".to_string(),
+        );
+        let data = "a->b\ninclude \"common.diag\"\n";
+        let options = TransformOptions::default();
+        let err = super::transform_with_resolver(data, &options, &files).unwrap_err();
+        let diagnostic = err.diagnostic();
+        assert_eq!(diagnostic.code, "E0003");
+        assert_eq!(
+            diagnostic.message,
+            "common.diag:3:8: opt fragments cannot have else branches"
+        );
+        assert_eq!(diagnostic.span.unwrap().start.line, 2);
+    }
+
+    #[test]
+    fn test_json_ast() {
+        let options = TransformOptions {
+            format: OutputFormat::JsonAst,
+            ..TransformOptions::default()
+        };
+        let output = super::transform_with_options("a->b: \"hi\"\n", &options).unwrap();
+        let ast: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let message = &ast["events"][0]["message"];
+        assert_eq!(message["source"]["participant"], "a");
+        assert_eq!(message["edge_style"], "continuous");
+        assert_eq!(message["payload"], "hi");
+    }
+
+    #[test]
+    fn test_geometry() {
+        let data = "alias a=\"Alice\"\na->b: \"hi\"\nb->]\n[->a\ndestroy b\n";
+        let options = TransformOptions::default();
+        let geometry = super::geometry(data, &options, &NoIncludes).unwrap();
+        let (alice, b) = (&geometry.participants[0], &geometry.participants[1]);
+        assert_eq!((&alice.id[..], &alice.name[..]), ("a", "Alice"));
+        assert!(alice.bounds.left + alice.bounds.width < b.bounds.left);
+        assert!(alice.foot_top.is_some());
+        // The destroyed participants are not mirrored below the lifelines
+        assert_eq!(b.foot_top, None);
+
+        let (hi, outgoing) = (&geometry.messages[0], &geometry.messages[1]);
+        assert_eq!(hi.source, MessageEnd::Participant("a".to_string()));
+        assert_eq!(hi.target, MessageEnd::Participant("b".to_string()));
+        assert!(hi.bounds.top >= alice.bounds.top + alice.bounds.height);
+        assert_eq!(outgoing.target, MessageEnd::RightBorder);
+        assert_eq!(geometry.messages[2].source, MessageEnd::LeftBorder);
+        assert!(outgoing.bounds.top > hi.bounds.top);
+        assert!(outgoing.bounds.left + outgoing.bounds.width <= geometry.width);
+
+        let options = TransformOptions {
+            format: OutputFormat::JsonLayout,
+            ..TransformOptions::default()
+        };
+        let output = super::transform_with_options(data, &options).unwrap();
+        let layout: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(layout["messages"][0]["source"]["participant"], "a");
+        assert_eq!(layout["messages"][1]["target"], "right_border");
+        assert_eq!(layout["messages"][2]["source"], "left_border");
     }
 }
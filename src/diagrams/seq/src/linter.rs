@@ -0,0 +1,259 @@
+use crate::parser::{
+    self, span_of, syntax_error, unescape, ParserError, Rule, SequenceDiagramParser,
+};
+use crate::renderer::collect_participants;
+use diagram_base::{Diagnostic, IncludeResolver, Span};
+use pest::Parser;
+use std::collections::HashSet;
+
+/// An alias declared in the linted file
+struct Declaration<'i> {
+    id: &'i str,
+    label: String,
+    /// The span of the ID
+    span: Span,
+    /// The span of the label
+    label_span: Span,
+}
+
+/// The number of single character edits turning one ID into the other, swapping two adjacent
+/// characters counts as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    distances[0] = (0..=b.len()).collect();
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Finds the undeclared participant whose ID is most likely a misspelling of the given one
+fn misspelling<'a>(id: &str, undeclared: &[&'a str]) -> Option<&'a str> {
+    let threshold = (id.chars().count() / 3).max(1);
+    undeclared
+        .iter()
+        .map(|candidate| (edit_distance(id, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// Finds the problems that do not prevent the diagram from being drawn, but probably make it look
+/// different than intended. The included files are taken into account, but the problems are only
+/// reported within the given input.
+pub fn lint(input: &str, resolver: &dyn IncludeResolver) -> Result<Vec<Diagnostic>, ParserError> {
+    let diag = parser::diagram(input, resolver)?;
+    let mut used = Vec::new();
+    collect_participants(&diag.events, &mut used);
+    let declared: HashSet<&str> = diag
+        .aliases
+        .iter()
+        .map(|alias| &alias.id[..])
+        .chain(diag.participants.iter().map(|id| &id[..]))
+        .collect();
+    let undeclared: Vec<&str> = used
+        .iter()
+        .copied()
+        .filter(|id| !declared.contains(id))
+        .collect();
+    let used: HashSet<&str> = used.into_iter().collect();
+
+    // The syntax tree keeps the spans of the elements, which the diagram does not
+    let main = SequenceDiagramParser::parse(Rule::main, input).map_err(syntax_error)?;
+    let mut declarations = Vec::new();
+    let mut diagnostics = Vec::new();
+    for pair in main.flatten() {
+        match pair.as_rule() {
+            Rule::alias => {
+                // { (^"alias" | participant_kind) ~ identifier ~ "=" ~ string }
+                let mut inner = pair
+                    .into_inner()
+                    .skip_while(|token| token.as_rule() == Rule::participant_kind);
+                let id = inner.next().unwrap();
+                let label = inner.next().unwrap();
+                declarations.push(Declaration {
+                    id: id.as_str(),
+                    label: unescape(&label)?,
+                    span: span_of(&id),
+                    label_span: span_of(&label),
+                });
+            }
+            Rule::pair | Rule::note | Rule::reference => {
+                let payload = pair
+                    .clone()
+                    .into_inner()
+                    .find(|token| token.as_rule() == Rule::string_inner);
+                let payload = match payload {
+                    Some(payload) => unescape(&payload)?,
+                    None => continue,
+                };
+                if payload.trim().is_empty() {
+                    let (message, help) = match pair.as_rule() {
+                        Rule::pair => (
+                            "empty message payload",
+                            "leave out the payload to draw the message without a label, e.g. a->b",
+                        ),
+                        Rule::note => ("empty note", "write the text of the note or remove it"),
+                        _ => (
+                            "empty reference label",
+                            "name the interaction the frame refers to",
+                        ),
+                    };
+                    diagnostics.push(
+                        Diagnostic::warning("W0004", message)
+                            .with_span(span_of(&pair))
+                            .with_help(help),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (i, declaration) in declarations.iter().enumerate() {
+        let id = declaration.id;
+        if !used.contains(id) {
+            let help = match misspelling(id, &undeclared) {
+                Some(typo) => format!("`{}` is used but never declared, is it a typo?", typo),
+                None => String::from("use the participant in the diagram or remove the alias"),
+            };
+            diagnostics.push(
+                Diagnostic::warning("W0001", &format!("alias `{}` is never used", id))
+                    .with_span(declaration.span)
+                    .with_help(&help),
+            );
+        }
+
+        // Either declared earlier in this file, or in an included file as well
+        let local = declarations.iter().filter(|other| other.id == id).count();
+        let total = diag.aliases.iter().filter(|alias| alias.id == id).count();
+        if declarations[..i].iter().any(|other| other.id == id) || total > local {
+            diagnostics.push(
+                Diagnostic::warning(
+                    "W0002",
+                    &format!("alias `{}` is declared more than once", id),
+                )
+                .with_span(declaration.span)
+                .with_help("only the label of the last declaration is used"),
+            );
+        }
+
+        let label = &declaration.label[..];
+        if label != id && (used.contains(label) || declared.contains(label)) {
+            diagnostics.push(
+                Diagnostic::warning(
+                    "W0003",
+                    &format!("the label of `{}` is the ID of another participant", id),
+                )
+                .with_span(declaration.label_span)
+                .with_help(&format!(
+                    "`{}` and `{}` are different participants, but may look alike",
+                    id, label
+                )),
+            );
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| {
+        diagnostic
+            .span
+            .map(|span| (span.start.line, span.start.column))
+    });
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use diagram_base::{NoIncludes, Position};
+    use std::collections::HashMap;
+
+    fn codes(input: &str) -> Vec<(String, usize)> {
+        lint(input, &NoIncludes)
+            .unwrap()
+            .into_iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.span.unwrap().start.line))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_clean_diagrams() {
+        let data = "alias a=\"Alice\"\nparticipant b\na->b: \"hi\"\nb->a\n";
+        assert!(codes(data).is_empty());
+    }
+
+    #[test]
+    fn reports_unused_aliases_with_typos() {
+        let data = "alias alice=\"Alice\"\nalias bob=\"Bob\"\nalcie->bob\n";
+        let diagnostics = lint(data, &NoIncludes).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, "W0001");
+        assert_eq!(
+            diagnostics[0].span,
+            Some(Span::new(Position::new(1, 7), Position::new(1, 12)))
+        );
+        assert_eq!(
+            diagnostics[0].help.as_deref(),
+            Some("`alcie` is used but never declared, is it a typo?")
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_aliases() {
+        let data = "alias a=\"A\"\nbox \"Box\" {\n  alias a=\"B\"\n}\na->a\n";
+        assert_eq!(codes(data), vec![("W0002".to_string(), 3)]);
+
+        let mut files = HashMap::new();
+        files.insert("common.diag".to_string(), "alias a=\"A\"\n".to_string());
+        let data = "include \"common.diag\"\nalias a=\"B\"\na->a\n";
+        let diagnostics = lint(data, &files).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span.unwrap().start.line, 2);
+    }
+
+    #[test]
+    fn reports_labels_colliding_with_ids() {
+        let data = "alias a=\"b\"\na->b\n";
+        assert_eq!(codes(data), vec![("W0003".to_string(), 1)]);
+        // A participant may be labelled with its own ID
+        assert!(codes("alias a=\"a\"\na->a\n").is_empty());
+    }
+
+    #[test]
+    fn reports_empty_payloads() {
+        let data = "a->b: \"\"\nopt {\n  note over a: \" \"\n}\nref over a: \"\"\n";
+        assert_eq!(
+            codes(data),
+            vec![
+                ("W0004".to_string(), 1),
+                ("W0004".to_string(), 3),
+                ("W0004".to_string(), 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(super::edit_distance("alice", "alcie"), 1);
+        assert_eq!(super::edit_distance("bob", "bobby"), 2);
+        assert_eq!(super::edit_distance("", "abc"), 3);
+        assert_eq!(
+            super::misspelling("alice", &["bob", "alcie"]),
+            Some("alcie")
+        );
+        assert_eq!(super::misspelling("bob", &["alcie"]), None);
+    }
+}
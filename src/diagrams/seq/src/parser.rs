@@ -1,130 +1,988 @@
+use ascii_canvas::display_width;
+use diagram_base::{IncludeResolver, Position, Span};
+use pest::error::{ErrorVariant, LineColLocation};
+use pest::iterators::{Pair, Pairs};
 use pest::Parser;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::str::Chars;
 
 #[derive(Parser)]
 #[grammar = "syntax.pest"]
 pub struct SequenceDiagramParser;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EdgeStyle {
     Continuous,
     Dashed,
 }
 
+/// The shape of the end of a message arrow
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArrowHead {
+    /// A synchronous call
+    Filled,
+    /// An asynchronous message
+    Open,
+    /// A message that failed or destroyed its recipient
+    Cross,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum EdgeDirection {
     Left,
     Right,
 }
 
-fn parse_edge(edge: &str) -> Result<(EdgeStyle, EdgeDirection), ParserError> {
-    match edge {
-        "->" => Ok((EdgeStyle::Continuous, EdgeDirection::Right)),
-        "-->" => Ok((EdgeStyle::Dashed, EdgeDirection::Right)),
-        "<-" => Ok((EdgeStyle::Continuous, EdgeDirection::Left)),
-        "<--" => Ok((EdgeStyle::Dashed, EdgeDirection::Left)),
-        _ => Err(ParserError::SyntaxError("Invalid edge".to_string())),
+#[derive(Debug, Clone, PartialEq)]
+struct Edge {
+    style: EdgeStyle,
+    direction: EdgeDirection,
+    head: ArrowHead,
+    /// Whether there is an arrowhead on both ends
+    bidirectional: bool,
+}
+
+fn parse_edge(edge: &Pair<Rule>) -> Result<Edge, ParserError> {
+    let arrow = edge.as_str();
+    let (points_left, points_right) = (arrow.starts_with('<'), arrow.ends_with('>'));
+    let head = match arrow {
+        "->" | "-->" | "<-" | "<--" | "<->" | "<-->" => ArrowHead::Filled,
+        "->>" | "-->>" | "<<-" | "<<--" => ArrowHead::Open,
+        "-x" | "--x" => ArrowHead::Cross,
+        _ => {
+            return Err(ParserError::SyntaxError(
+                "invalid arrow".to_string(),
+                span_of(edge),
+            ))
+        }
+    };
+    Ok(Edge {
+        style: match arrow.contains("--") {
+            true => EdgeStyle::Dashed,
+            false => EdgeStyle::Continuous,
+        },
+        direction: match points_left && !points_right {
+            true => EdgeDirection::Left,
+            false => EdgeDirection::Right,
+        },
+        head,
+        bidirectional: points_left && points_right,
+    })
+}
+
+/// Change of the activation state caused by a message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Activation {
+    /// The recipient becomes active
+    Activate,
+    /// The sender stops being active
+    Deactivate,
+}
+
+fn parse_activation(activation: &Pair<Rule>) -> Result<Activation, ParserError> {
+    match activation.as_str() {
+        "+" => Ok(Activation::Activate),
+        "-" => Ok(Activation::Deactivate),
+        _ => Err(ParserError::SyntaxError(
+            "invalid activation".to_string(),
+            span_of(activation),
+        )),
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FragmentKind {
+    Alt,
+    Opt,
+    Loop,
+    Par,
+    Critical,
+    Break,
+}
+
+impl FragmentKind {
+    /// Whether the fragment can be split into several sections using else
+    fn allows_else(&self) -> bool {
+        matches!(self, FragmentKind::Alt | FragmentKind::Par)
+    }
+}
+
+fn parse_fragment_kind(kind: &Pair<Rule>) -> Result<FragmentKind, ParserError> {
+    match kind.as_str().to_lowercase().as_str() {
+        "alt" => Ok(FragmentKind::Alt),
+        "opt" => Ok(FragmentKind::Opt),
+        "loop" => Ok(FragmentKind::Loop),
+        "par" => Ok(FragmentKind::Par),
+        "critical" => Ok(FragmentKind::Critical),
+        "break" => Ok(FragmentKind::Break),
+        _ => Err(ParserError::SyntaxError(
+            "invalid fragment".to_string(),
+            span_of(kind),
+        )),
+    }
+}
+
+/// The number of columns between two tab stops
+const TAB_WIDTH: usize = 4;
+
+/// Replaces the tabs with spaces up to the next tab stop, as the text is drawn on a grid of cells
+fn expand_tabs(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut line_start = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let column = display_width(&output[line_start..]);
+                output.push_str(&" ".repeat(TAB_WIDTH - column % TAB_WIDTH));
+            }
+            '\n' => {
+                output.push(c);
+                line_start = output.len();
+            }
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// Decodes the escape sequences of a string literal
+pub fn unescape(pair: &Pair<Rule>) -> Result<String, ParserError> {
+    let raw = pair.as_str();
+    let mut output = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    loop {
+        let offset = raw.len() - chars.as_str().len();
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break,
+        };
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match decode_escape(&mut chars) {
+            Some(decoded) => output.push_str(&decoded),
+            None => {
+                // Point at the offending escape sequence only
+                let sequence = &raw[offset..raw.len() - chars.as_str().len()];
+                let start = advance(start_of(pair), &raw[..offset]);
+                let span = Span::new(start, advance(start, sequence));
+                return Err(ParserError::InvalidEscape(sequence.to_string(), span));
+            }
+        }
+    }
+    Ok(expand_tabs(&output))
+}
+
+/// Decodes the escape sequence following a backslash. The control characters other than the line
+/// feed and the tab are not supported, as they would corrupt the drawing.
+fn decode_escape(chars: &mut Chars) -> Option<String> {
+    let decoded = match chars.next()? {
+        '"' => '"',
+        '\\' => '\\',
+        '/' => '/',
+        'n' => '\n',
+        't' => '\t',
+        'u' => {
+            let code_unit = |chars: &mut Chars| {
+                let digits = chars.take(4).collect::<String>();
+                match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    true => u16::from_str_radix(&digits, 16).ok(),
+                    false => None,
+                }
+            };
+            let mut code_units = vec![code_unit(chars)?];
+            // Characters outside of the basic multilingual plane are encoded as surrogate
+            // pairs, e.g. \ud83d\ude00
+            if (0xd800..0xdc00).contains(&code_units[0]) {
+                if chars.next() != Some('\\') || chars.next() != Some('u') {
+                    return None;
+                }
+                code_units.push(code_unit(chars)?);
+            }
+            let decoded = String::from_utf16(&code_units).ok()?;
+            if decoded
+                .chars()
+                .any(|c| c.is_control() && c != '\n' && c != '\t')
+            {
+                return None;
+            }
+            return Some(decoded);
+        }
+        _ => return None,
+    };
+    Some(decoded.to_string())
+}
+
+fn parse_number(number: &Pair<Rule>) -> Result<usize, ParserError> {
+    number
+        .as_str()
+        .parse()
+        .map_err(|_| ParserError::SyntaxError("number too large".to_string(), span_of(number)))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticipantKind {
+    Participant,
+    Actor,
+    Boundary,
+    Database,
+    Queue,
+}
+
+fn parse_participant_kind(kind: &Pair<Rule>) -> Result<ParticipantKind, ParserError> {
+    match kind.as_str().to_lowercase().as_str() {
+        "actor" => Ok(ParticipantKind::Actor),
+        "boundary" => Ok(ParticipantKind::Boundary),
+        "database" => Ok(ParticipantKind::Database),
+        "queue" => Ok(ParticipantKind::Queue),
+        _ => Err(ParserError::SyntaxError(
+            "invalid participant kind".to_string(),
+            span_of(kind),
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Alias {
     /// The ID of the node
     pub id: String,
     /// The label of the node
     pub label: String,
+    /// The kind of the node, determining how it is drawn
+    pub kind: ParticipantKind,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+fn parse_alias(alias: Pair<Rule>) -> Result<Alias, ParserError> {
+    // { (^"alias" | participant_kind) ~ name ~ "=" ~ string }
+    let mut inner_rules = alias.into_inner();
+
+    let kind = match inner_rules.peek().unwrap().as_rule() {
+        Rule::participant_kind => parse_participant_kind(&inner_rules.next().unwrap())?,
+        _ => ParticipantKind::Participant,
+    };
+    let name: &str = inner_rules.next().unwrap().as_str();
+    let value = unescape(&inner_rules.next().unwrap())?;
+
+    Ok(Alias {
+        id: String::from(name),
+        label: value,
+        kind,
+    })
+}
+
+/// A labelled frame around a group of adjacent participants
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParticipantBox {
+    /// The label drawn at the top of the frame
+    pub label: String,
+    /// The IDs of the participants in the box
+    pub participants: Vec<String>,
+}
+
+/// One end of a message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    /// The ID of a participant
+    Participant(String),
+    /// The left edge of the diagram, for the messages coming from or going to the outside
+    LeftBorder,
+    /// The right edge of the diagram, for the messages coming from or going to the outside
+    RightBorder,
+}
+
+fn parse_endpoint(endpoint: &Pair<Rule>) -> Endpoint {
+    match endpoint.as_rule() {
+        Rule::left_border => Endpoint::LeftBorder,
+        Rule::right_border => Endpoint::RightBorder,
+        _ => Endpoint::Participant(String::from(endpoint.as_str())),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Message {
     /// The sender of the message
-    pub source: String,
+    pub source: Endpoint,
     /// The style of the message arrow
     pub edge_style: EdgeStyle,
+    /// The shape of the end of the arrow pointing at the recipient
+    pub head: ArrowHead,
+    /// Whether the arrow points at the sender as well
+    pub bidirectional: bool,
     /// The recipient of the message
-    pub target: String,
+    pub target: Endpoint,
     /// The edge label
     pub payload: String,
+    /// The change of the activation state caused by the message
+    pub activation: Option<Activation>,
+    /// Whether the message creates its recipient
+    pub create: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotePlacement {
+    Left,
+    Right,
+    Over,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Note {
+    /// The placement of the note relative to the participants
+    pub placement: NotePlacement,
+    /// The participants the note is anchored to (two only when placed over a range)
+    pub participants: Vec<String>,
+    /// The note text
+    pub text: String,
+}
+
+/// A frame over the lifelines standing for an interaction described elsewhere
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reference {
+    /// The participants whose lifelines the frame spans
+    pub participants: Vec<String>,
+    /// The name of the referenced interaction
+    pub label: String,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FragmentSection {
+    /// The guard condition of the section
+    pub label: String,
+    /// List of events enclosed by the section
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Fragment {
+    /// The operator of the combined fragment
+    pub kind: FragmentKind,
+    /// List of sections, more than one only for the fragments supporting else branches
+    pub sections: Vec<FragmentSection>,
+}
+
+/// Numbering of the messages following the directive
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Autonumber {
+    /// The number of the first message
+    pub start: usize,
+    /// The difference between the numbers of two consecutive messages
+    pub step: usize,
+    /// The template of the number, `{}` is replaced with the number itself
+    pub format: String,
+    /// The location of the directive, to point at it if the numbers run out
+    pub span: Span,
+}
+
+/// An element of the diagram that occupies a place on the timeline
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Message(Message),
+    Note(Note),
+    Reference(Reference),
+    Fragment(Fragment),
+    /// Start of an explicit activation of a participant
+    Activate(String),
+    /// End of the most recent activation of a participant
+    Deactivate(String),
+    /// The participant is created by the next message sent to it
+    Create(String),
+    /// The lifeline of the participant ends
+    Destroy(String),
+    /// Start of the numbering of the succeeding messages
+    Autonumber(Autonumber),
+    /// A labelled line separating groups of messages
+    Divider(String),
+    /// A gap in time, with an optional label
+    Delay(String),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct SequenceDiagram {
+    /// The caption drawn above the diagram
+    pub title: Option<String>,
+    /// The text drawn at the very top of the diagram
+    pub header: Option<String>,
+    /// The text drawn at the very bottom of the diagram
+    pub footer: Option<String>,
+    /// Do not mirror the participants below the lifelines
+    pub hide_footbox: bool,
+    /// The IDs of the declared participants in the order they are declared, either with an alias,
+    /// within a box or in a participant list
+    pub participants: Vec<String>,
     /// List of aliases
     pub aliases: Vec<Alias>,
-    /// List of messages
-    pub messages: Vec<Message>,
+    /// The groups of participants drawn within a labelled frame
+    pub boxes: Vec<ParticipantBox>,
+    /// Tree of messages, notes and fragments in the order they appear
+    pub events: Vec<Event>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum ParserError {
-    SyntaxError(String),
+    /// The input does not match the grammar
+    SyntaxError(String, Span),
+    /// A string literal contains an unknown or malformed escape sequence
+    InvalidEscape(String, Span),
+    /// An else branch follows a fragment that only has a single section
+    UnexpectedElse(FragmentKind, Span),
+    /// The format of the message numbers has no placeholder for the number
+    InvalidNumberFormat(String, Span),
+    /// The included file cannot be found or read, with the reason why
+    IncludeFailed(String, String, Span),
+    /// The included file is already being included
+    IncludeCycle(String, Span),
+    /// The included file is invalid, the span points at the include statement
+    InIncludedFile(String, Box<ParserError>, Span),
+    /// An event involves a participant whose lifeline has already ended
+    UsedAfterDestroy(String, Span),
+    /// A participant is created after it has already been drawn
+    CreatedAfterUse(String, Span),
+    /// A participant is marked to be created, but no message is sent to it afterwards
+    NeverCreated(String, Span),
 }
 
-pub fn diagram(input: &str) -> Result<SequenceDiagram, ParserError> {
-    let ast = SequenceDiagramParser::parse(Rule::main, input);
-    match ast {
-        // TODO: rename
-        Ok(mut ast) => {
-            let mut diag = SequenceDiagram::default();
-            for stmt in ast.next().unwrap().into_inner() {
-                match stmt.as_rule() {
-                    Rule::alias => {
-                        // { ^"alias" ~ name ~ "=" ~ string }
-                        let mut inner_rules = stmt.into_inner();
-
-                        let name: &str = inner_rules.next().unwrap().as_str();
-                        let value: &str = inner_rules.next().unwrap().as_str();
-
-                        diag.aliases.push(Alias {
-                            id: String::from(name),
-                            label: String::from(value),
-                        });
+/// The files being included, used to resolve the nested includes and to detect cycles
+struct Includes<'a> {
+    resolver: &'a dyn IncludeResolver,
+    /// The names of the files being parsed, the innermost last. The input of the diagram itself
+    /// has no name unless the resolver gives it one.
+    stack: Vec<Option<String>>,
+}
+
+/// The participants that took part in the events so far, to catch the events that cannot be drawn
+#[derive(Default)]
+struct Lifecycle {
+    /// The participants involved in any of the events
+    appeared: HashSet<String>,
+    /// The participants whose lifeline has ended
+    destroyed: HashSet<String>,
+    /// The participants marked to be created by the next message sent to them, along with the
+    /// location of the mark
+    pending: Vec<(String, Span)>,
+}
+
+impl Lifecycle {
+    /// Checks the event against the preceding ones and records it
+    fn check(&mut self, event: Event, span: Span) -> Result<Event, ParserError> {
+        let mut used = Vec::new();
+        let mut created = None;
+        match &event {
+            Event::Message(message) => {
+                for endpoint in &[&message.source, &message.target] {
+                    if let Endpoint::Participant(id) = endpoint {
+                        used.push(id);
                     }
-                    Rule::pair => {
-                        // { name ~ edge ~ name ~ ":" ~ string }
-                        let mut inner_rules = stmt.into_inner();
-
-                        let source: &str = inner_rules.next().unwrap().as_str();
-                        let edge: &str = inner_rules.next().unwrap().as_str();
-
-                        // Swap source and target if needed
-                        let (edge_style, edge_direction) = parse_edge(edge)?;
-                        let target: &str = inner_rules.next().unwrap().as_str();
-                        let (source, target) = match edge_direction {
-                            EdgeDirection::Right => (source, target),
-                            EdgeDirection::Left => (target, source),
-                        };
-                        let label: &str = match inner_rules.peek() {
-                            Some(_) => inner_rules.next().unwrap().as_str(),
-                            None => "",
-                        };
-
-                        diag.messages.push(Message {
-                            source: String::from(source),
-                            target: String::from(target),
-                            payload: String::from(label),
-                            edge_style,
-                        });
+                }
+                if let (true, Endpoint::Participant(id)) = (message.create, &message.target) {
+                    created = Some(id);
+                }
+            }
+            Event::Note(Note { participants, .. })
+            | Event::Reference(Reference { participants, .. }) => used.extend(participants),
+            Event::Activate(id) | Event::Deactivate(id) | Event::Destroy(id) => used.push(id),
+            // The participant is created by the next message sent to it
+            Event::Create(id) => created = Some(id),
+            _ => (),
+        }
+
+        if let Some(id) = used.iter().find(|id| self.destroyed.contains(&id[..])) {
+            return Err(ParserError::UsedAfterDestroy(id.to_string(), span));
+        }
+        if let Some(id) = created.filter(|id| self.appeared.contains(&id[..])) {
+            return Err(ParserError::CreatedAfterUse(id.to_string(), span));
+        }
+        self.appeared.extend(used.into_iter().cloned());
+        match &event {
+            Event::Destroy(id) => {
+                self.destroyed.insert(id.clone());
+            }
+            Event::Create(id) => self.pending.push((id.clone(), span)),
+            // A message sent to the participant creates it, unless it is sent by the participant
+            // itself
+            Event::Message(Message {
+                source,
+                target: Endpoint::Participant(id),
+                ..
+            }) if source != &Endpoint::Participant(id.clone()) => {
+                self.pending.retain(|(pending, _)| pending != id)
+            }
+            _ => (),
+        }
+        Ok(event)
+    }
+
+    /// Checks that the participants marked to be created are created eventually
+    fn finish(self) -> Result<(), ParserError> {
+        match self.pending.into_iter().next() {
+            Some((id, span)) => Err(ParserError::NeverCreated(id, span)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Includes<'_> {
+    /// Parses the statements of the included file into the diagram
+    fn include(
+        &mut self,
+        path: &str,
+        span: Span,
+        diag: &mut SequenceDiagram,
+        lifecycle: &mut Lifecycle,
+    ) -> Result<Vec<Event>, ParserError> {
+        let includer = self.stack.last().cloned().flatten();
+        let include = self
+            .resolver
+            .resolve(path, includer.as_deref())
+            .map_err(|err| ParserError::IncludeFailed(path.to_string(), err.to_string(), span))?;
+        if self.stack.contains(&Some(include.name.clone())) {
+            return Err(ParserError::IncludeCycle(path.to_string(), span));
+        }
+
+        let in_included_file =
+            |err| ParserError::InIncludedFile(path.to_string(), Box::new(err), span);
+        let mut ast = SequenceDiagramParser::parse(Rule::main, &include.source)
+            .map_err(|err| in_included_file(syntax_error(err)))?;
+        self.stack.push(Some(include.name));
+        let events = statements(ast.next().unwrap().into_inner(), diag, self, lifecycle);
+        self.stack.pop();
+        events.map_err(in_included_file)
+    }
+}
+
+/// The location of the first character of the pair
+fn start_of(pair: &Pair<Rule>) -> Position {
+    let (line, column) = pair.as_span().start_pos().line_col();
+    Position::new(line, column)
+}
+
+/// The part of the source covered by the pair
+pub fn span_of(pair: &Pair<Rule>) -> Span {
+    let (line, column) = pair.as_span().end_pos().line_col();
+    Span::new(start_of(pair), Position::new(line, column))
+}
+
+/// Moves the position past the given text
+fn advance(mut position: Position, text: &str) -> Position {
+    for c in text.chars() {
+        match c {
+            '\n' => {
+                position.line += 1;
+                position.column = 1;
+            }
+            _ => position.column += 1,
+        }
+    }
+    position
+}
+
+/// A human readable name of the rule used in the syntax errors
+fn describe(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::EOI => "end of input",
+        Rule::identifier => "an identifier",
+        Rule::keyword => "a keyword",
+        Rule::string_inner | Rule::string_char => "a string",
+        Rule::string_end => "a closing quote",
+        Rule::edge => "an arrow",
+        Rule::activation => "an activation",
+        Rule::creation => "a creation",
+        Rule::participant_kind => "a participant kind",
+        Rule::fragment_kind => "a fragment",
+        Rule::block => "a block",
+        Rule::number => "a number",
+        Rule::divider_label | Rule::delay_label => "a label",
+        Rule::section => "a fragment section",
+        Rule::note_left | Rule::note_right | Rule::note_over => "a note placement",
+        _ => "a statement",
+    }
+}
+
+/// Lists the rules as "a, b, or c"
+fn enumerate(rules: &[Rule]) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for rule in rules {
+        let name = describe(rule);
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, [first])) => format!("{} or {}", first, last),
+        Some((last, rest)) => format!("{}, or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+pub fn syntax_error(err: pest::error::Error<Rule>) -> ParserError {
+    let span = match err.line_col {
+        LineColLocation::Pos((line, column)) => {
+            Span::new(Position::new(line, column), Position::new(line, column))
+        }
+        LineColLocation::Span((start_line, start_column), (end_line, end_column)) => Span::new(
+            Position::new(start_line, start_column),
+            Position::new(end_line, end_column),
+        ),
+    };
+    let message = match err.variant {
+        ErrorVariant::ParsingError {
+            positives,
+            negatives,
+        } => match (positives.is_empty(), negatives.is_empty()) {
+            (false, false) => format!(
+                "unexpected {}; expected {}",
+                enumerate(&negatives),
+                enumerate(&positives)
+            ),
+            (false, true) => format!("expected {}", enumerate(&positives)),
+            (true, false) => format!("unexpected {}", enumerate(&negatives)),
+            (true, true) => "invalid syntax".to_string(),
+        },
+        ErrorVariant::CustomError { message } => message,
+    };
+    ParserError::SyntaxError(message, span)
+}
+
+fn statements(
+    pairs: Pairs<Rule>,
+    diag: &mut SequenceDiagram,
+    includes: &mut Includes,
+    lifecycle: &mut Lifecycle,
+) -> Result<Vec<Event>, ParserError> {
+    let mut events = Vec::new();
+    for stmt in pairs {
+        let span = span_of(&stmt);
+        match stmt.as_rule() {
+            Rule::include => {
+                // { ^"include" ~ string }
+                let path = unescape(&stmt.into_inner().next().unwrap())?;
+                events.extend(includes.include(&path, span, diag, lifecycle)?);
+            }
+            Rule::title | Rule::header | Rule::footer => {
+                // { ^"title" ~ string }, the last one of each kind wins
+                let caption = match stmt.as_rule() {
+                    Rule::title => &mut diag.title,
+                    Rule::header => &mut diag.header,
+                    _ => &mut diag.footer,
+                };
+                *caption = Some(unescape(&stmt.into_inner().next().unwrap())?);
+            }
+            Rule::hide_footbox => diag.hide_footbox = true,
+            Rule::divider => {
+                // { "==" ~ divider_label ~ "==" }
+                let label = stmt.into_inner().next().unwrap().as_str().trim();
+                events.push(Event::Divider(String::from(label)));
+            }
+            Rule::delay => {
+                // { "..." ~ (delay_label ~ "...")? }
+                let label = match stmt.into_inner().next() {
+                    Some(label) => label.as_str().trim(),
+                    None => "",
+                };
+                events.push(Event::Delay(String::from(label)));
+            }
+            Rule::alias => {
+                let alias = parse_alias(stmt)?;
+                diag.participants.push(alias.id.clone());
+                diag.aliases.push(alias);
+            }
+            Rule::participant_list => {
+                // { ^"participant" ~ name ~ ("," ~ name)* }
+                stmt.into_inner()
+                    .for_each(|name| diag.participants.push(String::from(name.as_str())));
+            }
+            Rule::participant_box => {
+                // { ^"box" ~ string ~ "{" ~ (alias | name)* ~ "}" }
+                let mut inner_rules = stmt.clone().into_inner();
+
+                let label = unescape(&inner_rules.next().unwrap())?;
+                let mut participants = Vec::new();
+                for member in inner_rules {
+                    let id = match member.as_rule() {
+                        Rule::alias => {
+                            let alias = parse_alias(member.clone())?;
+                            let id = alias.id.clone();
+                            diag.aliases.push(alias);
+                            id
+                        }
+                        Rule::identifier => String::from(member.as_str()),
+                        _ => continue,
+                    };
+
+                    // A participant cannot be drawn within two frames
+                    let boxed = participants.contains(&id)
+                        || diag.boxes.iter().any(|b| b.participants.contains(&id));
+                    if boxed {
+                        return Err(ParserError::SyntaxError(
+                            format!("participant `{}` is already in a box", id),
+                            span_of(&member),
+                        ));
                     }
-                    Rule::EOI => (),
+                    diag.participants.push(id.clone());
+                    participants.push(id);
+                }
+
+                // There is nothing to draw the frame around
+                if participants.is_empty() {
+                    return Err(ParserError::SyntaxError(
+                        format!("box `{}` has no participants", label),
+                        span_of(&stmt),
+                    ));
+                }
+                diag.boxes.push(ParticipantBox {
+                    label,
+                    participants,
+                });
+            }
+            Rule::pair => {
+                // { (name | "[") ~ edge ~ creation? ~ activation? ~ (name | "]") ~ ":" ~ string }
+                let mut inner_rules = stmt.into_inner();
+
+                let source = parse_endpoint(&inner_rules.next().unwrap());
+                let edge = parse_edge(&inner_rules.next().unwrap())?;
+                let create = match inner_rules.peek().unwrap().as_rule() {
+                    Rule::creation => Some(inner_rules.next().unwrap()),
+                    _ => None,
+                };
+                let activation = match inner_rules.peek().unwrap().as_rule() {
+                    Rule::activation => Some(parse_activation(&inner_rules.next().unwrap())?),
+                    _ => None,
+                };
+                let target = parse_endpoint(&inner_rules.next().unwrap());
+                if source == Endpoint::LeftBorder && target == Endpoint::RightBorder {
+                    return Err(ParserError::SyntaxError(
+                        "a message needs at least one participant".to_string(),
+                        span,
+                    ));
+                }
+
+                // Swap source and target if needed
+                let (source, target) = match edge.direction {
+                    EdgeDirection::Right => (source, target),
+                    EdgeDirection::Left => (target, source),
+                };
+                if let Some(creation) = &create {
+                    let message = match (&source, &target) {
+                        (_, Endpoint::LeftBorder) | (_, Endpoint::RightBorder) => {
+                            Some("only a participant can be created")
+                        }
+                        (source, target) if source == target => {
+                            Some("a participant cannot create itself")
+                        }
+                        _ => None,
+                    };
+                    if let Some(message) = message {
+                        return Err(ParserError::SyntaxError(
+                            message.to_string(),
+                            span_of(creation),
+                        ));
+                    }
+                }
+                let label = match inner_rules.peek() {
+                    Some(_) => unescape(&inner_rules.next().unwrap())?,
+                    None => String::new(),
+                };
+
+                let message = Event::Message(Message {
+                    source,
+                    target,
+                    payload: label,
+                    edge_style: edge.style,
+                    head: edge.head,
+                    bidirectional: edge.bidirectional,
+                    activation,
+                    create: create.is_some(),
+                });
+                events.push(lifecycle.check(message, span)?);
+            }
+            Rule::autonumber => {
+                // { autonumber_keyword ~ (number ~ number?)? ~ string? }
+                let mut autonumber = Autonumber {
+                    start: 1,
+                    step: 1,
+                    format: "{}.".to_string(),
+                    span,
+                };
+                let mut numbers = 0;
+                for pair in stmt.into_inner().skip(1) {
+                    match pair.as_rule() {
+                        Rule::number => {
+                            match numbers {
+                                0 => autonumber.start = parse_number(&pair)?,
+                                _ => autonumber.step = parse_number(&pair)?,
+                            }
+                            numbers += 1;
+                        }
+                        Rule::string_inner => {
+                            autonumber.format = unescape(&pair)?;
+                            if !autonumber.format.contains("{}") {
+                                return Err(ParserError::InvalidNumberFormat(
+                                    autonumber.format,
+                                    span_of(&pair),
+                                ));
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                events.push(Event::Autonumber(autonumber));
+            }
+            Rule::activate => {
+                // { ^"activate" ~ name }
+                let name: &str = stmt.into_inner().next().unwrap().as_str();
+                events.push(lifecycle.check(Event::Activate(String::from(name)), span)?);
+            }
+            Rule::deactivate => {
+                // { ^"deactivate" ~ name }
+                let name: &str = stmt.into_inner().next().unwrap().as_str();
+                events.push(lifecycle.check(Event::Deactivate(String::from(name)), span)?);
+            }
+            Rule::create => {
+                // { ^"create" ~ name }
+                let name: &str = stmt.into_inner().next().unwrap().as_str();
+                events.push(lifecycle.check(Event::Create(String::from(name)), span)?);
+            }
+            Rule::destroy => {
+                // { ^"destroy" ~ name }
+                let name: &str = stmt.into_inner().next().unwrap().as_str();
+                events.push(lifecycle.check(Event::Destroy(String::from(name)), span)?);
+            }
+            Rule::note => {
+                // { ^"note" ~ (note_left | note_right | note_over) ~ ":" ~ string }
+                let mut inner_rules = stmt.into_inner();
+
+                let position = inner_rules.next().unwrap();
+                let placement = match position.as_rule() {
+                    Rule::note_left => NotePlacement::Left,
+                    Rule::note_right => NotePlacement::Right,
+                    Rule::note_over => NotePlacement::Over,
                     _ => unreachable!(),
+                };
+                let participants = position
+                    .into_inner()
+                    .map(|p| String::from(p.as_str()))
+                    .collect();
+                let text = unescape(&inner_rules.next().unwrap())?;
+
+                let note = Event::Note(Note {
+                    placement,
+                    participants,
+                    text,
+                });
+                events.push(lifecycle.check(note, span)?);
+            }
+            Rule::reference => {
+                // { ^"ref" ~ ^"over" ~ name ~ ("," ~ name)* ~ ":" ~ string }
+                let mut participants = Vec::new();
+                let mut label = String::new();
+                for pair in stmt.into_inner() {
+                    match pair.as_rule() {
+                        Rule::identifier => participants.push(String::from(pair.as_str())),
+                        Rule::string_inner => label = unescape(&pair)?,
+                        _ => (),
+                    }
                 }
+                let reference = Event::Reference(Reference {
+                    participants,
+                    label,
+                });
+                events.push(lifecycle.check(reference, span)?);
             }
+            Rule::fragment => {
+                // { fragment_kind ~ section ~ (^"else" ~ section)* }
+                let mut inner_rules = stmt.into_inner();
+
+                let kind = parse_fragment_kind(&inner_rules.next().unwrap())?;
+                let mut sections = Vec::new();
+                for section in inner_rules {
+                    if !sections.is_empty() && !kind.allows_else() {
+                        return Err(ParserError::UnexpectedElse(kind, span_of(&section)));
+                    }
+
+                    // { string? ~ block }
+                    let mut inner_rules = section.into_inner();
+                    let label = match inner_rules.peek().unwrap().as_rule() {
+                        Rule::string_inner => unescape(&inner_rules.next().unwrap())?,
+                        _ => String::new(),
+                    };
+                    let block = inner_rules.last().unwrap();
+                    sections.push(FragmentSection {
+                        label,
+                        events: statements(block.into_inner(), diag, includes, lifecycle)?,
+                    });
+                }
+                events.push(Event::Fragment(Fragment { kind, sections }));
+            }
+            Rule::EOI => (),
+            _ => unreachable!(),
+        }
+    }
+    Ok(events)
+}
+
+/// Parses the diagram, inlining the files it includes
+pub fn diagram(
+    input: &str,
+    resolver: &dyn IncludeResolver,
+) -> Result<SequenceDiagram, ParserError> {
+    let ast = SequenceDiagramParser::parse(Rule::main, input);
+    match ast {
+        Ok(mut ast) => {
+            let mut diag = SequenceDiagram::default();
+            let mut includes = Includes {
+                resolver,
+                stack: vec![resolver.name()],
+            };
+            let mut lifecycle = Lifecycle::default();
+            diag.events = statements(
+                ast.next().unwrap().into_inner(),
+                &mut diag,
+                &mut includes,
+                &mut lifecycle,
+            )?;
+            lifecycle.finish()?;
             Ok(diag)
         }
-        Err(e) => Err(ParserError::SyntaxError(e.to_string())),
+        Err(e) => Err(syntax_error(e)),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use diagram_base::NoIncludes;
+    use std::collections::HashMap;
+
+    fn diagram(input: &str) -> Result<SequenceDiagram, ParserError> {
+        super::diagram(input, &NoIncludes)
+    }
+
+    fn messages(diag: &SequenceDiagram) -> Vec<&Message> {
+        diag.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Message(message) => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
 
     #[test]
     fn parse_diagram_empty() {
         let data = "";
         let result = diagram(data).unwrap();
         assert_eq!(result.aliases.len(), 0);
-        assert_eq!(result.messages.len(), 0);
+        assert_eq!(result.events.len(), 0);
     }
 
     #[test]
@@ -135,55 +993,198 @@ mod test {
         "#;
         let result = diagram(data).unwrap();
         assert_eq!(result.aliases.len(), 0);
-        assert_eq!(result.messages.len(), 0);
+        assert_eq!(result.events.len(), 0);
     }
 
     #[test]
     fn parse_empty_message() {
         let data = "a->b";
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages.len(), 1);
-        assert_eq!(result.messages[0].payload, "");
+        assert_eq!(messages(&result).len(), 1);
+        assert_eq!(messages(&result)[0].payload, "");
     }
 
     #[test]
     fn parse_handles_directions() {
         let data = "a->b";
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages[0].source, "a");
-        assert_eq!(result.messages[0].target, "b");
+        assert_eq!(
+            messages(&result)[0].source,
+            Endpoint::Participant("a".to_string())
+        );
+        assert_eq!(
+            messages(&result)[0].target,
+            Endpoint::Participant("b".to_string())
+        );
         let data = "a<-b";
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages[0].source, "b");
-        assert_eq!(result.messages[0].target, "a");
+        assert_eq!(
+            messages(&result)[0].source,
+            Endpoint::Participant("b".to_string())
+        );
+        assert_eq!(
+            messages(&result)[0].target,
+            Endpoint::Participant("a".to_string())
+        );
     }
 
     #[test]
     fn parse_message_payload_with_unicode() {
         let data = r#"a->b: "𩸽""#;
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages.len(), 1);
-        assert_eq!(result.messages[0].payload, "𩸽");
+        assert_eq!(messages(&result).len(), 1);
+        assert_eq!(messages(&result)[0].payload, "𩸽");
     }
 
     #[test]
-    // TODO: ensure that we handle the escape sequences correctly
-    #[should_panic]
     fn parse_message_payload_with_escape_sequences() {
         let data = "a->b: \"\\\"hello\\\"\"\n";
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages.len(), 1);
-        assert_eq!(result.messages[0].payload, "\"hello\"");
+        assert_eq!(messages(&result).len(), 1);
+        assert_eq!(messages(&result)[0].payload, "\"hello\"");
+    }
+
+    #[test]
+    fn parse_arrowheads() {
+        let data = "a->b\na->>b\na<<--b\na-xb\na--x b\na<->b\na<-->b\n";
+        let result = diagram(data).unwrap();
+        let arrows = messages(&result)
+            .iter()
+            .map(|m| (m.edge_style.clone(), m.head.clone(), m.bidirectional))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            arrows,
+            vec![
+                (EdgeStyle::Continuous, ArrowHead::Filled, false),
+                (EdgeStyle::Continuous, ArrowHead::Open, false),
+                (EdgeStyle::Dashed, ArrowHead::Open, false),
+                (EdgeStyle::Continuous, ArrowHead::Cross, false),
+                (EdgeStyle::Dashed, ArrowHead::Cross, false),
+                (EdgeStyle::Continuous, ArrowHead::Filled, true),
+                (EdgeStyle::Dashed, ArrowHead::Filled, true),
+            ]
+        );
+        assert_eq!(
+            messages(&result)[2].source,
+            Endpoint::Participant("b".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_lost_and_found_messages() {
+        let data = "[->a\na->]\n[<-a\na<-]\n";
+        let result = diagram(data).unwrap();
+        let endpoints = messages(&result)
+            .iter()
+            .map(|m| (m.source.clone(), m.target.clone()))
+            .collect::<Vec<_>>();
+        let a = Endpoint::Participant("a".to_string());
+        assert_eq!(
+            endpoints,
+            vec![
+                (Endpoint::LeftBorder, a.clone()),
+                (a.clone(), Endpoint::RightBorder),
+                (a.clone(), Endpoint::LeftBorder),
+                (Endpoint::RightBorder, a),
+            ]
+        );
+    }
+
+    #[test]
+    fn disallows_messages_without_participants() {
+        assert!(diagram("[->]").is_err());
+        assert!(diagram("a->[").is_err());
+    }
+
+    #[test]
+    fn parse_create_and_destroy() {
+        let data = "create b\na->b\na->*+c\nd<-*a\ndestroy b\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events[0], Event::Create("b".to_string()));
+        assert_eq!(result.events[4], Event::Destroy("b".to_string()));
+        let created = messages(&result)
+            .iter()
+            .map(|m| (m.create, m.activation.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            created,
+            vec![
+                (false, None),
+                (true, Some(Activation::Activate)),
+                (true, None)
+            ]
+        );
+        assert_eq!(
+            messages(&result)[2].target,
+            Endpoint::Participant("d".to_string())
+        );
+    }
+
+    #[test]
+    fn disallows_events_after_destroy() {
+        assert_eq!(
+            diagram("a->b\ndestroy b\nnote over a, b: \"x\"").unwrap_err(),
+            ParserError::UsedAfterDestroy(
+                "b".to_string(),
+                Span::new(Position::new(3, 1), Position::new(3, 20))
+            )
+        );
+        assert!(diagram("a->b\ndestroy b\ndestroy b").is_err());
+        assert!(diagram("destroy b\nopt {\n  b->a\n}").is_err());
+        assert!(diagram("a->b\ndestroy b\na->c").is_ok());
+    }
+
+    #[test]
+    fn disallows_creation_after_use() {
+        assert_eq!(
+            diagram("a->b\ncreate b\na->b").unwrap_err(),
+            ParserError::CreatedAfterUse(
+                "b".to_string(),
+                Span::new(Position::new(2, 1), Position::new(2, 9))
+            )
+        );
+        assert!(diagram("activate b\na->*b").is_err());
+        assert!(diagram("create b\na->b").is_ok());
+    }
+
+    #[test]
+    fn disallows_creation_without_message() {
+        assert_eq!(
+            diagram("a->b\ncreate c\nc->a\nc->c").unwrap_err(),
+            ParserError::NeverCreated(
+                "c".to_string(),
+                Span::new(Position::new(2, 1), Position::new(2, 9))
+            )
+        );
+        assert!(diagram("create c\nopt {\n  a->*c\n}").is_ok());
+    }
+
+    #[test]
+    fn disallows_creating_borders_and_loops() {
+        assert_eq!(
+            diagram("a->*]").unwrap_err(),
+            ParserError::SyntaxError(
+                "only a participant can be created".to_string(),
+                Span::new(Position::new(1, 4), Position::new(1, 5))
+            )
+        );
+        assert_eq!(
+            diagram("a->*a").unwrap_err(),
+            ParserError::SyntaxError(
+                "a participant cannot create itself".to_string(),
+                Span::new(Position::new(1, 4), Position::new(1, 5))
+            )
+        );
     }
 
     #[test]
     fn parse_message_distinguishes_edge_style() {
         let data = r#"a->b"#;
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages[0].edge_style, EdgeStyle::Continuous);
+        assert_eq!(messages(&result)[0].edge_style, EdgeStyle::Continuous);
         let data = r#"a-->b"#;
         let result = diagram(data).unwrap();
-        assert_eq!(result.messages[0].edge_style, EdgeStyle::Dashed);
+        assert_eq!(messages(&result)[0].edge_style, EdgeStyle::Dashed);
     }
 
     #[test]
@@ -204,14 +1205,24 @@ mod test {
     fn allows_identifier_with_keyword_substring() {
         let data = "alias aliassson = \"aliasson\"";
         let result = diagram(data);
-        assert!(!result.is_err());
+        assert!(result.is_ok());
     }
 
     #[test]
     fn allows_underscores_in_identifiers() {
         let data = "alias _a_b_ = \"c\"";
         let result = diagram(data);
-        assert!(!result.is_err());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn allows_statement_keywords_as_identifiers() {
+        let data = "api->database\nqueue->worker\nuser->actor\nnote->alt\nautonumber->box";
+        let result = diagram(data).unwrap();
+        assert_eq!(messages(&result).len(), 5);
+        let data = "alias alias_1 = \"a\"\nnote_svc->create_user\nheader_parser->box1\nopt_in->ref";
+        let result = diagram(data).unwrap();
+        assert_eq!(messages(&result).len(), 3);
     }
 
     #[test]
@@ -220,4 +1231,489 @@ mod test {
         let result = diagram(data);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn parse_notes() {
+        let data = r#"
+        note left of a: "left"
+        note right of b: "right"
+        note over a, b: "over"
+        "#;
+        let result = diagram(data).unwrap();
+        assert_eq!(
+            result.events,
+            vec![
+                Event::Note(Note {
+                    placement: NotePlacement::Left,
+                    participants: vec!["a".to_string()],
+                    text: "left".to_string(),
+                }),
+                Event::Note(Note {
+                    placement: NotePlacement::Right,
+                    participants: vec!["b".to_string()],
+                    text: "right".to_string(),
+                }),
+                Event::Note(Note {
+                    placement: NotePlacement::Over,
+                    participants: vec!["a".to_string(), "b".to_string()],
+                    text: "over".to_string(),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_notes_interleaved_with_messages() {
+        let data = "a->b\nnote over b: \"hi\"\nb->a\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events.len(), 3);
+        assert!(matches!(result.events[1], Event::Note(_)));
+    }
+
+    #[test]
+    fn disallows_notes_over_more_than_two_participants() {
+        let data = "note over a, b, c: \"hi\"";
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_references() {
+        let data = "ref over a: \"Login\"\nopt {\n  ref over a, b, c: \"Authenticate\"\n}\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(
+            result.events[0],
+            Event::Reference(Reference {
+                participants: vec!["a".to_string()],
+                label: "Login".to_string(),
+            })
+        );
+        match &result.events[1] {
+            Event::Fragment(fragment) => assert_eq!(
+                fragment.sections[0].events,
+                vec![Event::Reference(Reference {
+                    participants: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                    label: "Authenticate".to_string(),
+                })]
+            ),
+            _ => panic!("expected a fragment"),
+        }
+    }
+
+    #[test]
+    fn disallows_references_without_participants() {
+        assert!(diagram("ref over: \"Login\"").is_err());
+        assert!(diagram("ref a: \"Login\"").is_err());
+    }
+
+    #[test]
+    fn parse_includes() {
+        let mut files = HashMap::new();
+        files.insert(
+            "common.diag".to_string(),
+            "title \"Shared\"\nalias a=\"Alice\"\ninclude \"nested.diag\"\n".to_string(),
+        );
+        files.insert("nested.diag".to_string(), "a->b\n".to_string());
+        let data = "include \"common.diag\"\nopt {\n  include \"nested.diag\"\n}\n";
+        let result = super::diagram(data, &files).unwrap();
+        assert_eq!(result.title, Some("Shared".to_string()));
+        assert_eq!(result.participants, vec!["a".to_string()]);
+        assert_eq!(result.events.len(), 2);
+        match &result.events[1] {
+            Event::Fragment(fragment) => assert_eq!(fragment.sections[0].events.len(), 1),
+            _ => panic!("expected a fragment"),
+        }
+    }
+
+    #[test]
+    fn disallows_include_cycles() {
+        let mut files = HashMap::new();
+        files.insert("a.diag".to_string(), "include \"b.diag\"".to_string());
+        files.insert("b.diag".to_string(), "a->b\ninclude \"a.diag\"".to_string());
+        match super::diagram("include \"a.diag\"", &files).unwrap_err() {
+            ParserError::InIncludedFile(path, err, span) => {
+                assert_eq!(path, "a.diag");
+                assert_eq!(span.start, Position::new(1, 1));
+                match *err {
+                    ParserError::InIncludedFile(_, err, _) => match *err {
+                        ParserError::IncludeCycle(path, span) => {
+                            assert_eq!(path, "a.diag");
+                            assert_eq!(span.start, Position::new(2, 1));
+                        }
+                        err => panic!("unexpected error {:?}", err),
+                    },
+                    err => panic!("unexpected error {:?}", err),
+                }
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn reports_missing_includes_at_the_include_site() {
+        let data = "a->b\ninclude \"missing.diag\"";
+        match super::diagram(data, &HashMap::new()).unwrap_err() {
+            ParserError::IncludeFailed(path, _, span) => {
+                assert_eq!(path, "missing.diag");
+                assert_eq!(span.start, Position::new(2, 1));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert!(diagram(data).is_err());
+    }
+
+    #[test]
+    fn parse_fragments() {
+        let data = r#"
+        alt "success" {
+            a->b
+            loop {
+                b->b
+            }
+        } else "failure" {
+            a->c
+        }
+        "#;
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events.len(), 1);
+        let fragment = match &result.events[0] {
+            Event::Fragment(fragment) => fragment,
+            _ => panic!("Expected a fragment"),
+        };
+        assert_eq!(fragment.kind, FragmentKind::Alt);
+        assert_eq!(fragment.sections.len(), 2);
+        assert_eq!(fragment.sections[0].label, "success");
+        assert_eq!(fragment.sections[0].events.len(), 2);
+        assert!(matches!(
+            fragment.sections[0].events[1],
+            Event::Fragment(Fragment {
+                kind: FragmentKind::Loop,
+                ..
+            })
+        ));
+        assert_eq!(fragment.sections[1].label, "failure");
+        assert_eq!(fragment.sections[1].events.len(), 1);
+    }
+
+    #[test]
+    fn parse_fragment_on_single_line() {
+        let data = r#"opt "maybe" { a->b }"#;
+        let result = diagram(data).unwrap();
+        assert!(matches!(
+            result.events[0],
+            Event::Fragment(Fragment {
+                kind: FragmentKind::Opt,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn disallows_else_in_single_section_fragments() {
+        let data = "opt { a->b } else { a->c }";
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn disallows_unclosed_fragments() {
+        let data = "loop {\na->b\n";
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn allows_missing_trailing_newline() {
+        let data = "a->b\nb->a";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events.len(), 2);
+    }
+
+    #[test]
+    fn parse_activations() {
+        let data = "activate a\na->+b\nb-->-a\ndeactivate a\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events[0], Event::Activate("a".to_string()));
+        assert_eq!(messages(&result)[0].activation, Some(Activation::Activate));
+        assert_eq!(
+            messages(&result)[1].activation,
+            Some(Activation::Deactivate)
+        );
+        assert_eq!(messages(&result)[1].edge_style, EdgeStyle::Dashed);
+        assert_eq!(result.events[3], Event::Deactivate("a".to_string()));
+    }
+
+    #[test]
+    fn parse_autonumber() {
+        let data = "autonumber\nautonumber 10\nautonumber 10 5 \"[{}]\"\n";
+        let result = diagram(data).unwrap();
+        let numberings: Vec<_> = result
+            .events
+            .iter()
+            .map(|event| match event {
+                Event::Autonumber(a) => (a.start, a.step, &a.format[..], a.span.start.line),
+                _ => panic!("Expected autonumber"),
+            })
+            .collect();
+        assert_eq!(
+            numberings,
+            vec![(1, 1, "{}.", 1), (10, 1, "{}.", 2), (10, 5, "[{}]", 3)]
+        );
+    }
+
+    #[test]
+    fn disallows_autonumber_format_without_placeholder() {
+        let data = "autonumber \"step\"";
+        let result = diagram(data);
+        assert_eq!(
+            result.unwrap_err(),
+            ParserError::InvalidNumberFormat(
+                "step".to_string(),
+                Span::new(Position::new(1, 13), Position::new(1, 17))
+            )
+        );
+    }
+
+    #[test]
+    fn parse_captions() {
+        let data = "title \"first\"\nheader \"top\"\ntitle \"second\"\nfooter \"bottom\"\na->b\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.title, Some("second".to_string()));
+        assert_eq!(result.header, Some("top".to_string()));
+        assert_eq!(result.footer, Some("bottom".to_string()));
+        assert_eq!(result.events.len(), 1);
+    }
+
+    #[test]
+    fn parse_hide_footbox() {
+        assert!(!diagram("a->b").unwrap().hide_footbox);
+        assert!(diagram("hide footbox\na->b").unwrap().hide_footbox);
+    }
+
+    #[test]
+    fn parse_dividers_and_delays() {
+        let data = "== Phase 2 ==\n====\n...\nloop {\n  ... 5 minutes later ...\n}\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.events[0], Event::Divider("Phase 2".to_string()));
+        assert_eq!(result.events[1], Event::Divider(String::new()));
+        assert_eq!(result.events[2], Event::Delay(String::new()));
+        match &result.events[3] {
+            Event::Fragment(fragment) => assert_eq!(
+                fragment.sections[0].events,
+                vec![Event::Delay("5 minutes later".to_string())]
+            ),
+            _ => panic!("expected a fragment"),
+        }
+    }
+
+    #[test]
+    fn parse_dividers_in_fragments() {
+        let data = "opt {\n  == Phase 2 ==\n}";
+        let result = diagram(data).unwrap();
+        match &result.events[0] {
+            Event::Fragment(fragment) => assert_eq!(
+                fragment.sections[0].events,
+                vec![Event::Divider("Phase 2".to_string())]
+            ),
+            _ => panic!("expected a fragment"),
+        }
+    }
+
+    #[test]
+    fn disallows_captions_in_fragments() {
+        let data = "opt {\n  title \"x\"\n}";
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_participant_list() {
+        let data = "participant c, a\nalias b = \"B\"\nparticipant d\na->b\n";
+        let result = diagram(data).unwrap();
+        assert_eq!(result.participants, vec!["c", "a", "b", "d"]);
+        assert_eq!(result.aliases.len(), 1);
+    }
+
+    #[test]
+    fn parse_participant_boxes() {
+        let data = r#"
+        box "Backend" {
+            alias api = "API"
+            database db = "DB"
+            cache
+        }
+        box "Clients" { web }
+        "#;
+        let result = diagram(data).unwrap();
+        assert_eq!(
+            result.boxes,
+            vec![
+                ParticipantBox {
+                    label: "Backend".to_string(),
+                    participants: vec!["api".to_string(), "db".to_string(), "cache".to_string()],
+                },
+                ParticipantBox {
+                    label: "Clients".to_string(),
+                    participants: vec!["web".to_string()],
+                },
+            ]
+        );
+        assert_eq!(result.aliases.len(), 2);
+        assert_eq!(result.aliases[1].kind, ParticipantKind::Database);
+    }
+
+    #[test]
+    fn disallows_participants_in_several_boxes() {
+        let data = "box \"A\" {\n  a\n}\nbox \"B\" {\n  b\n  a\n}";
+        assert_eq!(
+            diagram(data).unwrap_err(),
+            ParserError::SyntaxError(
+                "participant `a` is already in a box".to_string(),
+                Span::new(Position::new(6, 3), Position::new(6, 4))
+            )
+        );
+    }
+
+    #[test]
+    fn disallows_empty_boxes() {
+        let data = "box \"A\" {\n}\na->b";
+        assert_eq!(
+            diagram(data).unwrap_err(),
+            ParserError::SyntaxError(
+                "box `A` has no participants".to_string(),
+                Span::new(Position::new(1, 1), Position::new(2, 2))
+            )
+        );
+        assert!(diagram("box \"A\" {}").is_err());
+    }
+
+    #[test]
+    fn parse_participant_kinds() {
+        let data = r#"
+        alias a = "A"
+        actor b = "B"
+        boundary c = "C"
+        database d = "D"
+        queue e = "E"
+        "#;
+        let result = diagram(data).unwrap();
+        let kinds = result
+            .aliases
+            .iter()
+            .map(|a| a.kind.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ParticipantKind::Participant,
+                ParticipantKind::Actor,
+                ParticipantKind::Boundary,
+                ParticipantKind::Database,
+                ParticipantKind::Queue,
+            ]
+        );
+    }
+
+    #[test]
+    fn allows_identifier_with_participant_kind_prefix() {
+        let data = "actors->queues";
+        let result = diagram(data).unwrap();
+        assert_eq!(
+            messages(&result)[0].source,
+            Endpoint::Participant("actors".to_string())
+        );
+        assert_eq!(
+            messages(&result)[0].target,
+            Endpoint::Participant("queues".to_string())
+        );
+    }
+
+    #[test]
+    fn requires_a_space_after_participant_kind() {
+        let data = "actorabc = \"d\"";
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_escape_sequences() {
+        let data = r#"
+        alias a = "first\nsecond"
+        a->b: "\\ \/ \t é 😀"
+        "#;
+        let result = diagram(data).unwrap();
+        assert_eq!(result.aliases[0].label, "first\nsecond");
+        // The tab is expanded to the next tab stop
+        assert_eq!(messages(&result)[0].payload, "\\ /      é 😀");
+    }
+
+    #[test]
+    fn reports_syntax_error_location() {
+        let data = "a->b\nb c\n";
+        let result = diagram(data);
+        assert_eq!(
+            result.unwrap_err(),
+            ParserError::SyntaxError(
+                "expected an arrow".to_string(),
+                Span::new(Position::new(2, 3), Position::new(2, 3))
+            )
+        );
+    }
+
+    #[test]
+    fn reports_unterminated_strings() {
+        let data = "a->b: \"hello\nb->a\n";
+        let result = diagram(data);
+        assert_eq!(
+            result.unwrap_err(),
+            ParserError::SyntaxError(
+                "expected a closing quote".to_string(),
+                Span::new(Position::new(1, 13), Position::new(1, 13))
+            )
+        );
+    }
+
+    #[test]
+    fn reports_invalid_escape_location() {
+        let data = "a->b\nb->a: \"x \\q\"\n";
+        let result = diagram(data);
+        assert_eq!(
+            result.unwrap_err(),
+            ParserError::InvalidEscape(
+                "\\q".to_string(),
+                Span::new(Position::new(2, 10), Position::new(2, 12))
+            )
+        );
+    }
+
+    #[test]
+    fn disallows_control_characters() {
+        for sequence in &["\\r", "\\b", "\\f", "\\u000d", "\\u001b"] {
+            let data = format!("a->b: \"ab{}cd\"", sequence);
+            assert_eq!(
+                diagram(&data).unwrap_err(),
+                ParserError::InvalidEscape(
+                    sequence.to_string(),
+                    Span::new(Position::new(1, 10), Position::new(1, 10 + sequence.len()))
+                )
+            );
+        }
+        assert!(diagram("a->b: \"\\u0009\\u000a\"").is_ok());
+    }
+
+    #[test]
+    fn expands_tabs() {
+        assert_eq!(expand_tabs("\tab\tc"), "    ab  c");
+        assert_eq!(expand_tabs("abcd\te\n\tf"), "abcd    e\n    f");
+        // Wide characters take two columns
+        assert_eq!(expand_tabs("日本\tx"), "日本    x");
+        assert_eq!(expand_tabs("日\tx"), "日  x");
+    }
+
+    #[test]
+    fn disallows_lone_surrogates() {
+        let data = r#"a->b: "\ud83d""#;
+        let result = diagram(data);
+        assert!(result.is_err());
+    }
 }
@@ -1,23 +1,305 @@
 use crate::layout;
 use crate::parser;
+use diagram_base::{OutputFormat, Span, TransformOptions};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::iter::FromIterator;
 use std::sync::Arc;
 
-pub fn render(diag: parser::SequenceDiagram) -> String {
+type Participants = HashMap<String, Arc<layout::Participant>>;
+
+#[derive(Debug)]
+pub enum RenderError {
+    /// An event refers to a participant that was not collected
+    UnknownParticipant(String),
+    /// The layout could not be solved or drawn
+    Layout(layout::LayoutError),
+    /// The number of a message is too large, the span points at the numbering directive
+    NumberOverflow(Span),
+}
+
+impl From<layout::LayoutError> for RenderError {
+    fn from(err: layout::LayoutError) -> RenderError {
+        RenderError::Layout(err)
+    }
+}
+
+fn participant(
+    participants: &Participants,
+    id: &str,
+) -> Result<Arc<layout::Participant>, RenderError> {
+    participants
+        .get(id)
+        .cloned()
+        .ok_or_else(|| RenderError::UnknownParticipant(id.to_string()))
+}
+
+/// The numbering of the messages, carried across fragments so that the numbers follow the order of
+/// the messages in the source
+struct Numbering {
+    /// The number of the next message, none if it is too large to be represented
+    next: Option<usize>,
+    step: usize,
+    format: String,
+    span: Span,
+}
+
+impl Numbering {
+    /// Prefixes the payload with the number of the message and advances the numbering
+    fn label(&mut self, payload: &str) -> Result<String, RenderError> {
+        let next = self.next.ok_or(RenderError::NumberOverflow(self.span))?;
+        let number = self.format.replace("{}", &next.to_string());
+        self.next = next.checked_add(self.step);
+        Ok(match payload.is_empty() {
+            true => number,
+            false => format!("{} {}", number, payload),
+        })
+    }
+}
+
+fn endpoint(
+    participants: &Participants,
+    endpoint: &parser::Endpoint,
+) -> Result<layout::Endpoint, RenderError> {
+    Ok(match endpoint {
+        parser::Endpoint::Participant(id) => {
+            layout::Endpoint::Participant(participant(participants, id)?)
+        }
+        parser::Endpoint::LeftBorder => layout::Endpoint::LeftBorder,
+        parser::Endpoint::RightBorder => layout::Endpoint::RightBorder,
+    })
+}
+
+/// Collects the participants referenced by the events (including the nested ones) in the order
+/// they appear. The participants marked to be created appear with the message creating them.
+pub fn collect_participants<'a>(events: &'a [parser::Event], participants: &mut Vec<&'a str>) {
+    for event in events {
+        match event {
+            parser::Event::Message(m) => {
+                for endpoint in &[&m.source, &m.target] {
+                    if let parser::Endpoint::Participant(p) = endpoint {
+                        participants.push(p);
+                    }
+                }
+            }
+            parser::Event::Note(n) => n.participants.iter().for_each(|p| participants.push(p)),
+            parser::Event::Reference(r) => r.participants.iter().for_each(|p| participants.push(p)),
+            parser::Event::Fragment(f) => f
+                .sections
+                .iter()
+                .for_each(|s| collect_participants(&s.events, participants)),
+            parser::Event::Activate(p)
+            | parser::Event::Deactivate(p)
+            | parser::Event::Destroy(p) => participants.push(p),
+            parser::Event::Create(_)
+            | parser::Event::Autonumber(_)
+            | parser::Event::Divider(_)
+            | parser::Event::Delay(_) => {}
+        }
+    }
+}
+
+/// Collects the pairs of participants exchanging messages (including the nested ones), ignoring the
+/// messages coming from or going to the outside of the diagram
+fn collect_messages<'a>(events: &'a [parser::Event], messages: &mut Vec<(&'a str, &'a str)>) {
+    for event in events {
+        match event {
+            parser::Event::Message(m) => {
+                if let (
+                    parser::Endpoint::Participant(source),
+                    parser::Endpoint::Participant(target),
+                ) = (&m.source, &m.target)
+                {
+                    messages.push((source, target));
+                }
+            }
+            parser::Event::Fragment(f) => f
+                .sections
+                .iter()
+                .for_each(|s| collect_messages(&s.events, messages)),
+            _ => (),
+        }
+    }
+}
+
+/// Reorders the participants to minimise the total length of the messages, which is also the
+/// number of lifelines crossed by them. Finding the best order is NP-hard, so starting from the
+/// given order, the participants are moved one at a time to the position that shortens the
+/// messages until no such move is left.
+fn shorten_messages<'a>(participants: Vec<&'a str>, messages: &[(&str, &str)]) -> Vec<&'a str> {
+    // Count the messages exchanged by each pair of participants
+    let index = |id: &str| participants.iter().position(|p| *p == id);
+    let mut weights: HashMap<(usize, usize), usize> = HashMap::new();
+    for (source, target) in messages {
+        if let (Some(a), Some(b)) = (index(source), index(target)) {
+            if a != b {
+                *weights.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let length = |order: &[usize]| -> usize {
+        let mut positions = vec![0; order.len()];
+        order
+            .iter()
+            .enumerate()
+            .for_each(|(position, &p)| positions[p] = position);
+        weights
+            .iter()
+            .map(|(&(a, b), count)| count * positions[a].abs_diff(positions[b]))
+            .sum()
+    };
+
+    let mut order = (0..participants.len()).collect::<Vec<_>>();
+    let mut best = length(&order);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for from in 0..order.len() {
+            for to in 0..order.len() {
+                let mut candidate = order.clone();
+                let p = candidate.remove(from);
+                candidate.insert(to, p);
+                let candidate_length = length(&candidate);
+                if candidate_length < best {
+                    order = candidate;
+                    best = candidate_length;
+                    improved = true;
+                }
+            }
+        }
+    }
+    order.into_iter().map(|p| participants[p]).collect()
+}
+
+fn add_events(
+    layout: &mut layout::Layout,
+    events: Vec<parser::Event>,
+    participants: &Participants,
+    numbering: &mut Option<Numbering>,
+) -> Result<(), RenderError> {
+    for event in events {
+        match event {
+            parser::Event::Message(message) => layout.add_message(layout::Message {
+                source: endpoint(participants, &message.source)?,
+                target: endpoint(participants, &message.target)?,
+                payload: match numbering {
+                    Some(numbering) => numbering.label(&message.payload)?,
+                    None => message.payload,
+                },
+                edge_style: match message.edge_style {
+                    parser::EdgeStyle::Continuous => layout::EdgeStyle::Continuous,
+                    parser::EdgeStyle::Dashed => layout::EdgeStyle::Dashed,
+                },
+                head: match message.head {
+                    parser::ArrowHead::Filled => layout::ArrowHead::Filled,
+                    parser::ArrowHead::Open => layout::ArrowHead::Open,
+                    parser::ArrowHead::Cross => layout::ArrowHead::Cross,
+                },
+                bidirectional: message.bidirectional,
+                activation: message.activation.map(|activation| match activation {
+                    parser::Activation::Activate => layout::Activation::Activate,
+                    parser::Activation::Deactivate => layout::Activation::Deactivate,
+                }),
+                create: message.create,
+            }),
+            parser::Event::Note(note) => layout.add_note(layout::Note {
+                participants: note
+                    .participants
+                    .iter()
+                    .map(|p| participant(participants, p))
+                    .collect::<Result<_, _>>()?,
+                placement: match note.placement {
+                    parser::NotePlacement::Left => layout::NotePlacement::Left,
+                    parser::NotePlacement::Right => layout::NotePlacement::Right,
+                    parser::NotePlacement::Over => layout::NotePlacement::Over,
+                },
+                text: note.text,
+            }),
+            parser::Event::Reference(reference) => layout.add_reference(layout::Reference {
+                participants: reference
+                    .participants
+                    .iter()
+                    .map(|p| participant(participants, p))
+                    .collect::<Result<_, _>>()?,
+                label: reference.label,
+            }),
+            parser::Event::Fragment(fragment) => {
+                // Frame only the participants involved in the fragment
+                let mut involved = Vec::new();
+                fragment
+                    .sections
+                    .iter()
+                    .for_each(|s| collect_participants(&s.events, &mut involved));
+                if involved.is_empty() {
+                    continue;
+                }
+
+                layout.begin_fragment(layout::Fragment::new(
+                    match fragment.kind {
+                        parser::FragmentKind::Alt => layout::FragmentKind::Alt,
+                        parser::FragmentKind::Opt => layout::FragmentKind::Opt,
+                        parser::FragmentKind::Loop => layout::FragmentKind::Loop,
+                        parser::FragmentKind::Par => layout::FragmentKind::Par,
+                        parser::FragmentKind::Critical => layout::FragmentKind::Critical,
+                        parser::FragmentKind::Break => layout::FragmentKind::Break,
+                    },
+                    involved
+                        .iter()
+                        .map(|p| participant(participants, p))
+                        .collect::<Result<_, _>>()?,
+                    fragment.sections.iter().map(|s| s.label.clone()).collect(),
+                ));
+                for (idx, section) in fragment.sections.into_iter().enumerate() {
+                    if idx > 0 {
+                        layout.add_fragment_section();
+                    }
+                    add_events(layout, section.events, participants, numbering)?;
+                }
+                layout.end_fragment();
+            }
+            parser::Event::Activate(id) => layout.activate(&participant(participants, &id)?),
+            parser::Event::Deactivate(id) => layout.deactivate(&participant(participants, &id)?),
+            parser::Event::Create(id) => layout.create(&participant(participants, &id)?),
+            parser::Event::Destroy(id) => layout.destroy(&participant(participants, &id)?),
+            parser::Event::Divider(label) => layout.add_divider(layout::Divider { label }),
+            parser::Event::Delay(label) => layout.add_delay(layout::Delay { label }),
+            parser::Event::Autonumber(autonumber) => {
+                *numbering = Some(Numbering {
+                    next: Some(autonumber.start),
+                    step: autonumber.step,
+                    format: autonumber.format,
+                    span: autonumber.span,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lays out the elements of the diagram, leaving the constraints to be solved
+fn build(
+    diag: parser::SequenceDiagram,
+    options: &TransformOptions,
+) -> Result<layout::Layout, RenderError> {
+    // The index of the box the participant is drawn within
+    let boxes = &diag.boxes;
+    let box_of = |id: &str| {
+        boxes
+            .iter()
+            .position(|b| b.participants.iter().any(|p| p == id))
+    };
+
     // Gather all the unique participants in the following order:
-    // 1. Nodes with explicit aliases (this enables easy reordering by moving around aliases)
+    // 1. Declared nodes, either with an alias, within a box or in a participant list (this enables
+    //    easy reordering by moving around the declarations)
     // 2. The rest of the participants in the order they appear
     let ordered_participants = {
         let mut participants = Vec::new();
-        diag.aliases
+        diag.participants
             .iter()
-            .for_each(|a| participants.push(&a.id[..]));
-        diag.messages.iter().for_each(|m| {
-            participants.push(&m.source[..]);
-            participants.push(&m.target[..]);
-        });
+            .for_each(|p| participants.push(&p[..]));
+        collect_participants(&diag.events, &mut participants);
 
         // Deduplicate the participants
         let mut unique_participants: HashSet<&str> = HashSet::from_iter(participants.clone());
@@ -27,7 +309,30 @@ pub fn render(diag: parser::SequenceDiagram) -> String {
             .cloned()
             .filter(|p| unique_participants.remove(p))
             .collect();
-        participants
+
+        // Shorten the messages if requested, unless the order is declared
+        let participants = match options.reorder_participants && diag.participants.is_empty() {
+            true => {
+                let mut messages = Vec::new();
+                collect_messages(&diag.events, &mut messages);
+                shorten_messages(participants, &messages)
+            }
+            false => participants,
+        };
+
+        // Keep the participants of each box next to each other, at the position of the first one
+        let mut grouped: Vec<&str> = Vec::new();
+        for p in participants {
+            match box_of(p) {
+                Some(index) => boxes[index].participants.iter().for_each(|member| {
+                    if !grouped.contains(&&member[..]) {
+                        grouped.push(member);
+                    }
+                }),
+                None => grouped.push(p),
+            }
+        }
+        grouped
     };
 
     // Gather all the aliases. For the nodes without an explicit alias, set it to the alias to the
@@ -35,11 +340,12 @@ pub fn render(diag: parser::SequenceDiagram) -> String {
     let aliases = {
         let mut aliases = HashMap::new();
         diag.aliases.iter().for_each(|a| {
-            aliases.insert(&a.id[..], &a.label[..]);
+            aliases.insert(&a.id[..], (&a.label[..], &a.kind));
         });
-        diag.messages.iter().for_each(|m| {
-            aliases.entry(&m.source).or_insert(&m.source);
-            aliases.entry(&m.target).or_insert(&m.target);
+        ordered_participants.iter().for_each(|p| {
+            aliases
+                .entry(p)
+                .or_insert((p, &parser::ParticipantKind::Participant));
         });
         aliases
     };
@@ -48,52 +354,124 @@ pub fn render(diag: parser::SequenceDiagram) -> String {
     let participants = {
         let mut participants = HashMap::new();
         ordered_participants.iter().for_each(|p| {
+            let (name, kind) = aliases.get(p).unwrap();
             participants.insert(
                 p.to_string(),
                 Arc::from(layout::Participant {
                     id: p.to_string(),
-                    name: aliases.get(p).unwrap().to_string(),
+                    name: name.to_string(),
+                    kind: match kind {
+                        parser::ParticipantKind::Participant => {
+                            layout::ParticipantKind::Participant
+                        }
+                        parser::ParticipantKind::Actor => layout::ParticipantKind::Actor,
+                        parser::ParticipantKind::Boundary => layout::ParticipantKind::Boundary,
+                        parser::ParticipantKind::Database => layout::ParticipantKind::Database,
+                        parser::ParticipantKind::Queue => layout::ParticipantKind::Queue,
+                    },
                 }),
             );
         });
         participants
     };
 
-    // Construct the layout
-    let mut layout = layout::Layout::new();
-    for participant in ordered_participants {
-        layout.add_participant(participants.get(participant).unwrap().clone());
+    // Construct the layout. The footbox can be hidden by the diagram itself as well.
+    let options = TransformOptions {
+        hide_footbox: options.hide_footbox || diag.hide_footbox,
+        ..options.clone()
+    };
+    let mut layout = layout::Layout::new(options.clone());
+    if let Some(title) = diag.title {
+        layout.set_title(title);
     }
-    for message in diag.messages {
-        layout.add_message(layout::Message {
-            source: participants.get(&message.source).unwrap().clone(),
-            target: participants.get(&message.target).unwrap().clone(),
-            payload: message.payload,
-            edge_style: match message.edge_style {
-                parser::EdgeStyle::Continuous => layout::EdgeStyle::Continuous,
-                parser::EdgeStyle::Dashed => layout::EdgeStyle::Dashed,
-            },
-        })
+    if let Some(header) = diag.header {
+        layout.set_header(header);
+    }
+    if let Some(footer) = diag.footer {
+        layout.set_footer(footer);
+    }
+    let mut open_box = None;
+    for id in ordered_participants {
+        let participant_box = box_of(id);
+        if participant_box != open_box {
+            if open_box.is_some() {
+                layout.end_box();
+            }
+            if let Some(index) = participant_box {
+                layout.begin_box(layout::ParticipantBox {
+                    label: boxes[index].label.clone(),
+                });
+            }
+            open_box = participant_box;
+        }
+        layout.add_participant(participant(&participants, id)?);
+    }
+    if open_box.is_some() {
+        layout.end_box();
     }
+    add_events(&mut layout, diag.events, &participants, &mut None)?;
+    Ok(layout)
+}
+
+pub fn render(
+    diag: parser::SequenceDiagram,
+    options: &TransformOptions,
+) -> Result<String, RenderError> {
+    let output = match options.format {
+        // The diagram is exported as is, without laying it out
+        OutputFormat::JsonAst => serde_json::to_string_pretty(&diag).unwrap(),
+        OutputFormat::JsonLayout => {
+            serde_json::to_string_pretty(&geometry(diag, options)?).unwrap()
+        }
+        OutputFormat::Ascii => build(diag, options)?.render()?,
+        OutputFormat::Svg => build(diag, options)?.render_svg()?,
+    };
+    Ok(output)
+}
 
-    layout.render()
+/// Computes the positions and sizes of the elements of the diagram
+pub fn geometry(
+    diag: parser::SequenceDiagram,
+    options: &TransformOptions,
+) -> Result<layout::Geometry, RenderError> {
+    let geometry = build(diag, options)?.geometry()?;
+    Ok(geometry)
 }
 
 #[cfg(test)]
 mod test {
     use crate::parser;
+    use diagram_base::TransformOptions;
 
     #[test]
     fn smoke() {
         let mut diag = parser::SequenceDiagram::default();
+        diag.participants.push("test1".to_string());
+        diag.participants.push("test2".to_string());
         diag.aliases.push(parser::Alias {
             id: "test1".to_string(),
             label: "label".to_string(),
+            kind: parser::ParticipantKind::Participant,
         });
         diag.aliases.push(parser::Alias {
             id: "test2".to_string(),
             label: "label".to_string(),
+            kind: parser::ParticipantKind::Actor,
         });
-        super::render(diag);
+        super::render(diag, &TransformOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn shorten_messages() {
+        let messages = vec![("a", "c"), ("c", "a"), ("b", "d"), ("a", "b")];
+        assert_eq!(
+            super::shorten_messages(vec!["a", "b", "c", "d"], &messages),
+            vec!["c", "a", "b", "d"]
+        );
+        // The order is kept when no move shortens the messages
+        assert_eq!(
+            super::shorten_messages(vec!["a", "b", "c"], &[("a", "b"), ("b", "c")]),
+            vec!["a", "b", "c"]
+        );
     }
 }
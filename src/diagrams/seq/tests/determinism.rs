@@ -0,0 +1,60 @@
+use pretty_assertions::assert_eq;
+
+/// The number of times every fixture is rendered
+const RUNS: usize = 50;
+
+/// Diagrams that have several equally optimal layouts
+const FIXTURES: &[&str] = &[
+    r#"
+    a-->c: "x"
+    note over d: "xxxxxxxxxxxx"
+    d-->a: "xxxxxxxxxxxxxx"
+    "#,
+    r#"
+    b->d: "xxxx"
+    e-->a: "xx"
+    c-->e: "xxxxxxxxxxxxxx"
+    b-->c: "xxxx"
+    note left of a: "xxxxxxxxxxxxx"
+    "#,
+    r#"
+    d-->a: "xxxxxxxxxx"
+    d-->a: "xxxxx"
+    note left of a: "xxx"
+    d->a: "xx"
+    d-->c: "xxxxxxxxx"
+    "#,
+    r#"
+    actor u = "User"
+    database db = "Database"
+    u->+api: "request"
+    alt "cached" {
+        api-->u: "response"
+    } else "miss" {
+        api->db: "query"
+        note over api, db: "slow"
+        db-->api: "rows"
+    }
+    deactivate api
+    "#,
+    r#"
+    actor u = "User"
+    box "Svc" {
+    alias api = "API"
+    }
+    autonumber
+    u->+api: "req"
+    alt "ok" {
+    }
+    "#,
+];
+
+#[test]
+fn test_layout_is_deterministic() {
+    for fixture in FIXTURES {
+        let expected = diagram_seq::transform(fixture).unwrap();
+        for _ in 0..RUNS {
+            assert_eq!(diagram_seq::transform(fixture).unwrap(), expected);
+        }
+    }
+}
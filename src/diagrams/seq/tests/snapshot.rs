@@ -1,4 +1,6 @@
+use diagram_base::{Charset, OutputFormat, TransformOptions};
 use pretty_assertions::assert_eq;
+use std::collections::HashMap;
 use std::fmt;
 
 #[derive(PartialEq, Eq)]
@@ -82,7 +84,6 @@ fn test_two_participants_with_message() {
             r#"
             a->b: "hello world"
             "#
-            .into()
         ),
         concat!(
             "┌────┐           ┌────┐ \n",
@@ -113,7 +114,6 @@ fn test_complex() {
             c->b: "hello back too"
             b->b: "hello?"
             "#
-            .into()
         ),
         concat!(
             "┌────────┐        ┌──────┐         ┌──────────┐ \n",
@@ -141,3 +141,1008 @@ fn test_complex() {
         ),
     );
 }
+
+#[test]
+fn test_notes() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "hello"
+            note left of a: "l"
+            note right of b: "r"
+            note over a, b: "both"
+            "#
+        ),
+        concat!(
+            "    ┌────┐     ┌────┐      \n",
+            "    │ a  │     │ b  │      \n",
+            "    └────┘     └────┘      \n",
+            "       │  hello   │        \n",
+            "       │─────────▶│        \n",
+            "       │          │        \n",
+            "┌────┐ │          │        \n",
+            "│ l  │ │          │        \n",
+            "└────┘ │          │        \n",
+            "       │          │        \n",
+            "       │          │ ┌────┐ \n",
+            "       │          │ │ r  │ \n",
+            "       │          │ └────┘ \n",
+            "       │          │        \n",
+            "     ┌──────────────┐      \n",
+            "     │     both     │      \n",
+            "     └──────────────┘      \n",
+            "       │          │        \n",
+            "    ┌────┐     ┌────┐      \n",
+            "    │ a  │     │ b  │      \n",
+            "    └────┘     └────┘      ",
+        ),
+    );
+}
+
+#[test]
+fn test_fragments() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "request"
+            alt "ok" {
+                b-->a: "200"
+            } else "error" {
+                loop {
+                    b->b: "retry"
+                }
+            }
+            "#
+        ),
+        concat!(
+            "┌────┐       ┌────┐               \n",
+            "│ a  │       │ b  │               \n",
+            "└────┘       └────┘               \n",
+            "   │  request   │                 \n",
+            "   │───────────▶│                 \n",
+            "   │            │                 \n",
+            " ┌─ alt [ok] ───────────────────┐ \n",
+            " │ │    200     │               │ \n",
+            " │ │◀-----------│               │ \n",
+            " │ │            │               │ \n",
+            " ├╌ [error] ╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌┤ \n",
+            " │ │          ┌─ loop ────────┐ │ \n",
+            " │ │          │ │─┐           │ │ \n",
+            " │ │          │ │ │ retry     │ │ \n",
+            " │ │          │ │◀┘           │ │ \n",
+            " │ │          │ │             │ │ \n",
+            " │ │          └───────────────┘ │ \n",
+            " └──────────────────────────────┘ \n",
+            "┌────┐       ┌────┐               \n",
+            "│ a  │       │ b  │               \n",
+            "└────┘       └────┘               ",
+        ),
+    );
+}
+
+#[test]
+fn test_activations() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->+b: "call"
+            b->+b: "self"
+            b->a: "ping"
+            deactivate b
+            b-->-a: "done"
+            "#
+        ),
+        concat!(
+            "┌────┐   ┌────┐         \n",
+            "│ a  │   │ b  │         \n",
+            "└────┘   └────┘         \n",
+            "   │  call  │           \n",
+            "   │───────▶┃           \n",
+            "   │        ┃           \n",
+            "   │        ┃─┐         \n",
+            "   │        ┃ │ self    \n",
+            "   │        ┃◀┘         \n",
+            "   │        ┃┃          \n",
+            "   │  ping  ┃┃          \n",
+            "   │◀───────┃┃          \n",
+            "   │        ┃           \n",
+            "   │  done  ┃           \n",
+            "   │◀-------┃           \n",
+            "   │        │           \n",
+            "┌────┐   ┌────┐         \n",
+            "│ a  │   │ b  │         \n",
+            "└────┘   └────┘         ",
+        ),
+    );
+}
+
+#[test]
+fn test_activation_without_messages() {
+    // There is no lifeline to draw the bar on
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            activate a
+            "#
+        ),
+        concat!(
+            "┌────┐ \n",
+            "│ a  │ \n",
+            "└────┘ \n",
+            "┌────┐ \n",
+            "│ a  │ \n",
+            "└────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_participant_kinds() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            actor u = "User"
+            database db = "DB"
+            queue q = "Q"
+            boundary b = "B"
+            u->db
+            db->q
+            q->b
+            "#
+        ),
+        concat!(
+            "   O   ╭────╮        │ ╭───╮  \n",
+            "  /|\\  ├────┤ ┌───┬╮ ├─┤   │  \n",
+            "  / \\  │ DB │ │ Q ││ │ ╰───╯  \n",
+            " User  ╰────╯ └───┴╯    B     \n",
+            "   │      │      │       │    \n",
+            "   │─────▶│      │       │    \n",
+            "   │      │      │       │    \n",
+            "   │      │      │       │    \n",
+            "   │      │─────▶│       │    \n",
+            "   │      │      │       │    \n",
+            "   │      │      │       │    \n",
+            "   │      │      │──────▶│    \n",
+            "   │      │      │       │    \n",
+            "   O   ╭────╮ ┌───┬╮ │ ╭───╮  \n",
+            "  /|\\  ├────┤ │ Q ││ ├─┤   │  \n",
+            "  / \\  │ DB │ └───┴╯ │ ╰───╯  \n",
+            " User  ╰────╯           B     ",
+        ),
+    );
+}
+
+#[test]
+fn test_multiline_labels() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            alias a = "Alice\nSmith"
+            a->b: "first\nsecond"
+            b->b: "think\n\"hard\""
+            note right of a: "one\ntwo"
+            "#
+        ),
+        concat!(
+            "┌────────┐                    \n",
+            "│ Alice  │   ┌────┐           \n",
+            "│ Smith  │   │ b  │           \n",
+            "└────────┘   └────┘           \n",
+            "     │  first   │             \n",
+            "     │  second  │             \n",
+            "     │─────────▶│             \n",
+            "     │          │             \n",
+            "     │          │─┐           \n",
+            "     │          │ │ think     \n",
+            "     │          │ │ \"hard\"    \n",
+            "     │          │◀┘           \n",
+            "     │          │             \n",
+            "     │ ┌──────┐ │             \n",
+            "     │ │ one  │ │             \n",
+            "     │ │ two  │ │             \n",
+            "     │ └──────┘ │             \n",
+            "     │          │             \n",
+            "┌────────┐   ┌────┐           \n",
+            "│ Alice  │   │ b  │           \n",
+            "│ Smith  │   └────┘           \n",
+            "└────────┘                    ",
+        ),
+    );
+}
+
+#[test]
+fn test_tabs_in_labels() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "id\tname\n1\tAlice"
+            "#
+        ),
+        concat!(
+            "┌────┐         ┌────┐ \n",
+            "│ a  │         │ b  │ \n",
+            "└────┘         └────┘ \n",
+            "   │   id  name   │   \n",
+            "   │  1   Alice   │   \n",
+            "   │─────────────▶│   \n",
+            "   │              │   \n",
+            "┌────┐         ┌────┐ \n",
+            "│ a  │         │ b  │ \n",
+            "└────┘         └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_wrapped_labels() {
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"
+            a->b: "please wrap this long payload"
+            b->b: "and this one too"
+            "#,
+            &TransformOptions {
+                max_label_width: Some(10),
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            "┌────┐         ┌────┐             \n",
+            "│ a  │         │ b  │             \n",
+            "└────┘         └────┘             \n",
+            "   │    please    │               \n",
+            "   │  wrap this   │               \n",
+            "   │     long     │               \n",
+            "   │   payload    │               \n",
+            "   │─────────────▶│               \n",
+            "   │              │               \n",
+            "   │              │─┐             \n",
+            "   │              │ │ and this    \n",
+            "   │              │ │ one too     \n",
+            "   │              │◀┘             \n",
+            "   │              │               \n",
+            "┌────┐         ┌────┐             \n",
+            "│ a  │         │ b  │             \n",
+            "└────┘         └────┘             ",
+        ),
+    );
+}
+
+#[test]
+fn test_ascii_charset() {
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"
+            database d = "D"
+            queue q = "Q"
+            a->+d: "hi"
+            d-->q
+            q->q: "loop"
+            note over a: "n"
+            alt "c" {
+              a->q
+            } else {
+              q->a
+            }
+            "#,
+            &TransformOptions {
+                charset: Charset::Ascii,
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            ".----.                   \n",
+            "+----+ +---+.     +----+ \n",
+            "| D  | | Q ||     | a  | \n",
+            "'----' +---+'     +----+ \n",
+            "   |      |hi        |   \n",
+            "   #<----------------|   \n",
+            "   #      |          |   \n",
+            "   #      |          |   \n",
+            "   #.....>|          |   \n",
+            "   #      |          |   \n",
+            "   #      |-+        |   \n",
+            "   #      | | loop   |   \n",
+            "   #      |<+        |   \n",
+            "   #      |          |   \n",
+            "   #      |       +----+ \n",
+            "   #      |       | n  | \n",
+            "   #      |       +----+ \n",
+            "   #      |          |   \n",
+            "   #    +- alt [c] ----+ \n",
+            "   #    | |          | | \n",
+            "   #    | |<---------| | \n",
+            "   #    | |          | | \n",
+            "   #    +..............+ \n",
+            "   #    | |          | | \n",
+            "   #    | |--------->| | \n",
+            "   #    | |          | | \n",
+            "   #    +--------------+ \n",
+            ".----. +---+.     +----+ \n",
+            "+----+ | Q ||     | a  | \n",
+            "| D  | +---+'     +----+ \n",
+            "'----'                   ",
+        ),
+    );
+}
+
+#[test]
+fn test_rounded_charset() {
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"
+            boundary b = "B"
+            a->b: "hi"
+            b->b: "loop"
+            opt {
+              note over a, b: "n"
+            }
+            "#,
+            &TransformOptions {
+                charset: Charset::Rounded,
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            "│ ╭───╮              \n",
+            "├─┤   │     ╭────╮   \n",
+            "│ ╰───╯     │ a  │   \n",
+            "   B        ╰────╯   \n",
+            "    │    hi    │     \n",
+            "    │◀─────────│     \n",
+            "    │          │     \n",
+            "    │─╮        │     \n",
+            "    │ │ loop   │     \n",
+            "    │◀╯        │     \n",
+            "    │          │     \n",
+            "╭─ opt ────────────╮ \n",
+            "│ ╭──────────────╮ │ \n",
+            "│ │      n       │ │ \n",
+            "│ ╰──────────────╯ │ \n",
+            "│   │          │   │ \n",
+            "╰──────────────────╯ \n",
+            "│ ╭───╮     ╭────╮   \n",
+            "├─┤   │     │ a  │   \n",
+            "│ ╰───╯     ╰────╯   \n",
+            "   B                 ",
+        ),
+    );
+}
+
+#[test]
+fn test_autonumber() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            autonumber
+            a->b: "request"
+            alt "ok" {
+              b-->a: "response"
+            } else {
+              loop {
+                b->b: "retry"
+              }
+            }
+            autonumber 10 5 "[{}]"
+            b-->a
+            "#
+        ),
+        concat!(
+            "┌────┐           ┌────┐                 \n",
+            "│ a  │           │ b  │                 \n",
+            "└────┘           └────┘                 \n",
+            "   │   1. request   │                   \n",
+            "   │───────────────▶│                   \n",
+            "   │                │                   \n",
+            " ┌─ alt [ok] ─────────────────────────┐ \n",
+            " │ │  2. response   │                 │ \n",
+            " │ │◀---------------│                 │ \n",
+            " │ │                │                 │ \n",
+            " ├╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌╌┤ \n",
+            " │ │              ┌─ loop ──────────┐ │ \n",
+            " │ │              │ │─┐             │ │ \n",
+            " │ │              │ │ │ 3. retry    │ │ \n",
+            " │ │              │ │◀┘             │ │ \n",
+            " │ │              │ │               │ │ \n",
+            " │ │              └─────────────────┘ │ \n",
+            " └────────────────────────────────────┘ \n",
+            "   │      [10]      │                   \n",
+            "   │◀---------------│                   \n",
+            "   │                │                   \n",
+            "┌────┐           ┌────┐                 \n",
+            "│ a  │           │ b  │                 \n",
+            "└────┘           └────┘                 ",
+        ),
+    );
+}
+
+#[test]
+fn test_captions() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            header "ACME internal"
+            title "Login flow"
+            footer "page 1"
+            a->b: "hi"
+            "#
+        ),
+        concat!(
+            "ACME internal \n",
+            "              \n",
+            "  Login flow  \n",
+            "              \n",
+            "┌────┐ ┌────┐ \n",
+            "│ a  │ │ b  │ \n",
+            "└────┘ └────┘ \n",
+            "   │  hi  │   \n",
+            "   │─────▶│   \n",
+            "   │      │   \n",
+            "┌────┐ ┌────┐ \n",
+            "│ a  │ │ b  │ \n",
+            "└────┘ └────┘ \n",
+            "              \n",
+            "    page 1    ",
+        ),
+    );
+}
+
+#[test]
+fn test_hide_footbox() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            hide footbox
+            a->b: "hi"
+            "#
+        ),
+        concat!(
+            "┌────┐ ┌────┐ \n",
+            "│ a  │ │ b  │ \n",
+            "└────┘ └────┘ \n",
+            "   │  hi  │   \n",
+            "   │─────▶│   \n",
+            "   │      │   ",
+        ),
+    );
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"a->b: "hi""#,
+            &TransformOptions {
+                hide_footbox: true,
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            "┌────┐ ┌────┐ \n",
+            "│ a  │ │ b  │ \n",
+            "└────┘ └────┘ \n",
+            "   │  hi  │   \n",
+            "   │─────▶│   \n",
+            "   │      │   ",
+        ),
+    );
+}
+
+#[test]
+fn test_dividers_and_delays() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->+b: "login"
+            ... 5 minutes later ...
+            b-->-a: "ok"
+            == Phase 2 ==
+            a->b: "logout"
+            ...
+            "#
+        ),
+        concat!(
+            "┌────┐     ┌────┐ \n",
+            "│ a  │     │ b  │ \n",
+            "└────┘     └────┘ \n",
+            "   │  login   │   \n",
+            "   │─────────▶┃   \n",
+            "   │          ┃   \n",
+            "   ┊          ┊   \n",
+            " 5 minutes later  \n",
+            "   ┊          ┊   \n",
+            "   │    ok    ┃   \n",
+            "   │◀---------┃   \n",
+            "   │          │   \n",
+            "════ Phase 2 ═════\n",
+            "   │          │   \n",
+            "   │  logout  │   \n",
+            "   │─────────▶│   \n",
+            "   │          │   \n",
+            "   ┊          ┊   \n",
+            "   ┊          ┊   \n",
+            "┌────┐     ┌────┐ \n",
+            "│ a  │     │ b  │ \n",
+            "└────┘     └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_dividers_in_fragments() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "x"
+            opt "retry" {
+                == Phase 2 ==
+                b->a: "y"
+            }
+            "#
+        ),
+        concat!(
+            "┌────┐      ┌────┐ \n",
+            "│ a  │      │ b  │ \n",
+            "└────┘      └────┘ \n",
+            "   │     x     │   \n",
+            "   │──────────▶│   \n",
+            "   │           │   \n",
+            " ┌─ opt [retry] ─┐ \n",
+            " │═══ Phase 2 ═══│ \n",
+            " │ │           │ │ \n",
+            " │ │     y     │ │ \n",
+            " │ │◀──────────│ │ \n",
+            " │ │           │ │ \n",
+            " └───────────────┘ \n",
+            "┌────┐      ┌────┐ \n",
+            "│ a  │      │ b  │ \n",
+            "└────┘      └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_arrow_types() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            [->a: "found"
+            a->>b: "async"
+            a<->b: "both"
+            b--x a: "failed"
+            b->]: "lost"
+            "#
+        ),
+        concat!(
+            "       ┌────┐     ┌────┐       \n",
+            "       │ a  │     │ b  │       \n",
+            "       └────┘     └────┘       \n",
+            "  found   │          │         \n",
+            "─────────▶│          │         \n",
+            "          │          │         \n",
+            "          │  async   │         \n",
+            "          │─────────▷│         \n",
+            "          │          │         \n",
+            "          │   both   │         \n",
+            "          │◀────────▶│         \n",
+            "          │          │         \n",
+            "          │  failed  │         \n",
+            "          │×---------│         \n",
+            "          │          │         \n",
+            "          │          │  lost   \n",
+            "          │          │───────▶ \n",
+            "          │          │         \n",
+            "       ┌────┐     ┌────┐       \n",
+            "       │ a  │     │ b  │       \n",
+            "       └────┘     └────┘       ",
+        ),
+    );
+}
+
+#[test]
+fn test_create_and_destroy() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "spawn"
+            a->*c: "new"
+            c->b: "work"
+            b-->>c: "done"
+            destroy c
+            b->a: "ok"
+            "#
+        ),
+        concat!(
+            "┌────┐     ┌────┐          \n",
+            "│ a  │     │ b  │          \n",
+            "└────┘     └────┘          \n",
+            "   │  spawn   │            \n",
+            "   │─────────▶│            \n",
+            "   │          │            \n",
+            "   │      new │     ┌────┐ \n",
+            "   │───────────────▶│ c  │ \n",
+            "   │          │     └────┘ \n",
+            "   │          │  work  │   \n",
+            "   │          │◀───────│   \n",
+            "   │          │        │   \n",
+            "   │          │  done  │   \n",
+            "   │          │-------▷×   \n",
+            "   │          │            \n",
+            "   │    ok    │            \n",
+            "   │◀─────────│            \n",
+            "   │          │            \n",
+            "┌────┐     ┌────┐          \n",
+            "│ a  │     │ b  │          \n",
+            "└────┘     └────┘          ",
+        ),
+    );
+}
+
+#[test]
+fn test_destroy_before_messages() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            activate a
+            destroy a
+            "#
+        ),
+        concat!(
+            "┌────┐ \n",
+            "│ a  │ \n",
+            "└────┘ \n",
+            "   ┃   \n",
+            "   ×   ",
+        ),
+    );
+}
+
+#[test]
+fn test_destroy_first_in_fragment() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b
+            alt "retry" {
+                destroy b
+                a->a
+            }
+            "#
+        ),
+        concat!(
+            "┌────┐     ┌────┐ \n",
+            "│ a  │     │ b  │ \n",
+            "└────┘     └────┘ \n",
+            "   │          │   \n",
+            "   │─────────▶│   \n",
+            "   │          │   \n",
+            " ┌─ alt [retry] ┐ \n",
+            " │ │          × │ \n",
+            " │ │─┐          │ \n",
+            " │ │ │          │ \n",
+            " │ │◀┘          │ \n",
+            " │ │            │ \n",
+            " └──────────────┘ \n",
+            "┌────┐            \n",
+            "│ a  │            \n",
+            "└────┘            ",
+        ),
+    );
+}
+
+#[test]
+fn test_create_in_fragment() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b
+            loop "jobs" {
+                a->*c: "new"
+                c->b
+            }
+            "#
+        ),
+        concat!(
+            "┌────┐ ┌────┐          \n",
+            "│ a  │ │ b  │          \n",
+            "└────┘ └────┘          \n",
+            "   │      │            \n",
+            "   │─────▶│            \n",
+            "   │      │            \n",
+            " ┌─ loop [jobs] ─────┐ \n",
+            " │ │   new│   ┌────┐ │ \n",
+            " │ │─────────▶│ c  │ │ \n",
+            " │ │      │   └────┘ │ \n",
+            " │ │      │      │   │ \n",
+            " │ │      │◀─────│   │ \n",
+            " │ │      │      │   │ \n",
+            " └───────────────────┘ \n",
+            "┌────┐ ┌────┐ ┌────┐   \n",
+            "│ a  │ │ b  │ │ c  │   \n",
+            "└────┘ └────┘ └────┘   ",
+        ),
+    );
+}
+
+#[test]
+fn test_destroy_after_creation() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->*c: "new"
+            destroy c
+            a->b
+            "#
+        ),
+        concat!(
+            "┌────┐             ┌────┐ \n",
+            "│ a  │             │ b  │ \n",
+            "└────┘             └────┘ \n",
+            "   │  new   ┌────┐    │   \n",
+            "   │───────▶│ c  │    │   \n",
+            "   │        └────┘    │   \n",
+            "   │           ×      │   \n",
+            "   │                  │   \n",
+            "   │─────────────────▶│   \n",
+            "   │                  │   \n",
+            "┌────┐             ┌────┐ \n",
+            "│ a  │             │ b  │ \n",
+            "└────┘             └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_participant_boxes() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            box "Backend" {
+                alias api = "API"
+                db
+            }
+            client->api: "GET /"
+            api->db: "query"
+            api-->client: "200"
+            "#
+        ),
+        concat!(
+            "╭───── Backend ──────╮            \n",
+            "│ ┌──────┐    ┌────┐ │ ┌────────┐ \n",
+            "│ │ API  │    │ db │ │ │ client │ \n",
+            "│ └──────┘    └────┘ │ └────────┘ \n",
+            "│     │        GET / │      │     \n",
+            "│     │◀────────────────────│     \n",
+            "│     │          │   │      │     \n",
+            "│     │  query   │   │      │     \n",
+            "│     │─────────▶│   │      │     \n",
+            "│     │          │   │      │     \n",
+            "│     │         200  │      │     \n",
+            "│     │--------------------▶│     \n",
+            "│     │          │   │      │     \n",
+            "│ ┌──────┐    ┌────┐ │ ┌────────┐ \n",
+            "│ │ API  │    │ db │ │ │ client │ \n",
+            "│ └──────┘    └────┘ │ └────────┘ \n",
+            "╰────────────────────╯            ",
+        ),
+    );
+}
+
+#[test]
+fn test_participant_list() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            participant c, b
+            a->b: "one"
+            b->c: "two"
+            "#
+        ),
+        concat!(
+            "┌────┐   ┌────┐   ┌────┐ \n",
+            "│ c  │   │ b  │   │ a  │ \n",
+            "└────┘   └────┘   └────┘ \n",
+            "   │        │  one   │   \n",
+            "   │        │◀───────│   \n",
+            "   │        │        │   \n",
+            "   │  two   │        │   \n",
+            "   │◀───────│        │   \n",
+            "   │        │        │   \n",
+            "┌────┐   ┌────┐   ┌────┐ \n",
+            "│ c  │   │ b  │   │ a  │ \n",
+            "└────┘   └────┘   └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_reorder_participants() {
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"
+            a->b: "ping"
+            a->c: "work"
+            c-->a: "done"
+            a->c: "more"
+            "#,
+            &TransformOptions {
+                reorder_participants: true,
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            "┌────┐   ┌────┐   ┌────┐ \n",
+            "│ b  │   │ a  │   │ c  │ \n",
+            "└────┘   └────┘   └────┘ \n",
+            "   │  ping  │        │   \n",
+            "   │◀───────│        │   \n",
+            "   │        │        │   \n",
+            "   │        │  work  │   \n",
+            "   │        │───────▶│   \n",
+            "   │        │        │   \n",
+            "   │        │  done  │   \n",
+            "   │        │◀-------│   \n",
+            "   │        │        │   \n",
+            "   │        │  more  │   \n",
+            "   │        │───────▶│   \n",
+            "   │        │        │   \n",
+            "┌────┐   ┌────┐   ┌────┐ \n",
+            "│ b  │   │ a  │   │ c  │ \n",
+            "└────┘   └────┘   └────┘ ",
+        ),
+    );
+}
+
+#[test]
+fn test_references() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            a->b: "hello"
+            ref over a, b: "Authenticate"
+            b->c: "fetch"
+            ref over c: "Load"
+            "#
+        ),
+        concat!(
+            "┌────┐       ┌────┐     ┌────┐   \n",
+            "│ a  │       │ b  │     │ c  │   \n",
+            "└────┘       └────┘     └────┘   \n",
+            "   │   hello    │          │     \n",
+            "   │───────────▶│          │     \n",
+            "   │            │          │     \n",
+            " ┌─ ref ──────────┐        │     \n",
+            " │  Authenticate  │        │     \n",
+            " └────────────────┘        │     \n",
+            "   │            │          │     \n",
+            "   │            │  fetch   │     \n",
+            "   │            │─────────▶│     \n",
+            "   │            │          │     \n",
+            "   │            │     ┌─ ref ──┐ \n",
+            "   │            │     │  Load  │ \n",
+            "   │            │     └────────┘ \n",
+            "   │            │          │     \n",
+            "┌────┐       ┌────┐     ┌────┐   \n",
+            "│ a  │       │ b  │     │ c  │   \n",
+            "└────┘       └────┘     └────┘   ",
+        ),
+    );
+}
+
+#[test]
+fn test_svg() {
+    assert_renders!(
+        diagram_seq::transform_with_options(
+            r#"
+            a->b: "<hi>"
+            b-->a
+            "#,
+            &TransformOptions {
+                format: OutputFormat::Svg,
+                ..TransformOptions::default()
+            }
+        ),
+        concat!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"128\" height=\"192\" viewBox=\"0 0 128 192\">\n",
+            "<defs>\n",
+            "<style>\n",
+            "text { font-family: monospace; font-size: 13px; dominant-baseline: central; }\n",
+            ".background { fill: #fff; }\n",
+            ".shape { fill: #fff; stroke: #000; }\n",
+            ".note { fill: #ffc; stroke: #000; }\n",
+            ".group { fill: #f4f4f4; stroke: #888; }\n",
+            ".activation { fill: #ddd; stroke: #000; }\n",
+            ".line { fill: none; stroke: #000; }\n",
+            ".lifeline { stroke: #888; stroke-dasharray: 4 4; }\n",
+            ".dashed { stroke-dasharray: 4 3; }\n",
+            ".dotted { stroke-dasharray: 1 3; }\n",
+            ".label { font-weight: bold; }\n",
+            ".title { font-weight: bold; }\n",
+            ".caption { fill: #555; }\n",
+            "#arrowhead { fill: #000; }\n",
+            "</style>\n",
+            "<marker id=\"arrowhead\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"8\" markerHeight=\"8\" orient=\"auto-start-reverse\"><path d=\"M 0 0 L 10 5 L 0 10 z\"/></marker>\n",
+            "<marker id=\"open-arrowhead\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"8\" markerHeight=\"8\" orient=\"auto-start-reverse\"><path d=\"M 0 0 L 10 5 L 0 10\" class=\"line\"/></marker>\n",
+            "<marker id=\"cross\" viewBox=\"0 0 10 10\" refX=\"5\" refY=\"5\" markerWidth=\"8\" markerHeight=\"8\"><path d=\"M 0 0 L 10 10 M 10 0 L 0 10\" class=\"line\"/></marker>\n",
+            "</defs>\n",
+            "<rect width=\"100%\" height=\"100%\" class=\"background\"/>\n",
+            "<line x1=\"28\" y1=\"48\" x2=\"28\" y2=\"144\" class=\"line lifeline\"/>\n",
+            "<rect x=\"8\" y=\"8\" width=\"40\" height=\"32\" class=\"shape\"/>\n",
+            "<text x=\"28\" y=\"24\" text-anchor=\"middle\" xml:space=\"preserve\">a</text>\n",
+            "<rect x=\"8\" y=\"152\" width=\"40\" height=\"32\" class=\"shape\"/>\n",
+            "<text x=\"28\" y=\"168\" text-anchor=\"middle\" xml:space=\"preserve\">a</text>\n",
+            "<line x1=\"100\" y1=\"48\" x2=\"100\" y2=\"144\" class=\"line lifeline\"/>\n",
+            "<rect x=\"80\" y=\"8\" width=\"40\" height=\"32\" class=\"shape\"/>\n",
+            "<text x=\"100\" y=\"24\" text-anchor=\"middle\" xml:space=\"preserve\">b</text>\n",
+            "<rect x=\"80\" y=\"152\" width=\"40\" height=\"32\" class=\"shape\"/>\n",
+            "<text x=\"100\" y=\"168\" text-anchor=\"middle\" xml:space=\"preserve\">b</text>\n",
+            "<path d=\"M 28 72 H 100\" class=\"line\" marker-end=\"url(#arrowhead)\"/>\n",
+            "<text x=\"64\" y=\"56\" text-anchor=\"middle\" xml:space=\"preserve\">&lt;hi&gt;</text>\n",
+            "<path d=\"M 100 120 H 28\" class=\"line dashed\" marker-end=\"url(#arrowhead)\"/>\n",
+            "</svg>",
+        ),
+    );
+}
+
+#[test]
+fn test_wide_characters() {
+    assert_renders!(
+        diagram_seq::transform(
+            r#"
+            alias a = "日本語"
+            a->b: "😀 emoji"
+            b->b: "漢字"
+            "#
+        ),
+        concat!(
+            "┌────────┐     ┌────┐         \n",
+            "│ 日本語 │     │ b  │         \n",
+            "└────────┘     └────┘         \n",
+            "     │  😀 emoji  │           \n",
+            "     │───────────▶│           \n",
+            "     │            │           \n",
+            "     │            │─┐         \n",
+            "     │            │ │ 漢字    \n",
+            "     │            │◀┘         \n",
+            "     │            │           \n",
+            "┌────────┐     ┌────┐         \n",
+            "│ 日本語 │     │ b  │         \n",
+            "└────────┘     └────┘         ",
+        ),
+    );
+}
+
+#[test]
+fn test_includes() {
+    let mut files = HashMap::new();
+    files.insert(
+        "common/participants.diag".to_string(),
+        "alias a=\"Alice\"\nalias b=\"Bob\"\n".to_string(),
+    );
+    files.insert(
+        "common/ping.diag".to_string(),
+        "a->b: \"ping\"\n".to_string(),
+    );
+    assert_renders!(
+        diagram_seq::transform_with_resolver(
+            r#"
+            include "common/participants.diag"
+            opt {
+                include "common/ping.diag"
+            }
+            "#,
+            &TransformOptions::default(),
+            &files,
+        ),
+        concat!(
+            "┌────────┐ ┌──────┐ \n",
+            "│ Alice  │ │ Bob  │ \n",
+            "└────────┘ └──────┘ \n",
+            "     │         │    \n",
+            "   ┌─ opt ───────┐  \n",
+            "   │ │  ping   │ │  \n",
+            "   │ │────────▶│ │  \n",
+            "   │ │         │ │  \n",
+            "   └─────────────┘  \n",
+            "┌────────┐ ┌──────┐ \n",
+            "│ Alice  │ │ Bob  │ \n",
+            "└────────┘ └──────┘ ",
+        ),
+    );
+}
@@ -0,0 +1,39 @@
+use lsp_server::{Connection, Message};
+use std::error::Error;
+
+mod server;
+
+/// Handles the messages until the editor shuts the server down. The connection is dropped
+/// afterwards, so that the thread writing the responses stops.
+fn main_loop(connection: Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let mut server = server::Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = server.handle_request(request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                for notification in server.handle_notification(notification) {
+                    connection
+                        .sender
+                        .send(Message::Notification(notification))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    // The editor talks to the server over STDIN and STDOUT
+    let (connection, io_threads) = Connection::stdio();
+    connection.initialize(serde_json::to_value(server::capabilities())?)?;
+    main_loop(connection)?;
+    io_threads.join()?;
+    Ok(())
+}
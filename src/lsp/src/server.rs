@@ -0,0 +1,619 @@
+use diagram_base::{FileResolver, OutputFormat, TransformOptions};
+use diagram_seq::{Analysis, Occurrence};
+use lsp_server::{ErrorCode, Notification, Request, Response, ResponseError};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as LspNotification, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, ExecuteCommand, GotoDefinition, HoverRequest, Rename, Request as LspRequest,
+};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    DiagnosticSeverity, ExecuteCommandOptions, ExecuteCommandParams, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location,
+    MarkupContent, MarkupKind, NumberOrString, OneOf, Position, PublishDiagnosticsParams, Range,
+    RenameParams, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit,
+    Url, WorkspaceEdit,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Renders a document, the arguments are its URI and optionally the format (ascii by default)
+pub const PREVIEW_COMMAND: &str = "diagwiz.preview";
+
+/// The keywords starting the statements
+const KEYWORDS: &[&str] = &[
+    "activate",
+    "actor",
+    "alias",
+    "alt",
+    "autonumber",
+    "boundary",
+    "box",
+    "break",
+    "create",
+    "critical",
+    "database",
+    "deactivate",
+    "destroy",
+    "else",
+    "footer",
+    "header",
+    "hide footbox",
+    "include",
+    "loop",
+    "note",
+    "opt",
+    "par",
+    "participant",
+    "queue",
+    "ref",
+    "title",
+];
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions::default()),
+        definition_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        execute_command_provider: Some(ExecuteCommandOptions {
+            commands: vec![PREVIEW_COMMAND.to_string()],
+            ..ExecuteCommandOptions::default()
+        }),
+        ..ServerCapabilities::default()
+    }
+}
+
+/// Converts a position in a diagram, counted in characters from 1, to a position in the editor,
+/// counted in UTF-16 code units from 0
+fn to_lsp(text: &str, position: diagram_base::Position) -> Position {
+    let line = position.line.saturating_sub(1);
+    let character: usize = text
+        .lines()
+        .nth(line)
+        .unwrap_or("")
+        .chars()
+        .take(position.column.saturating_sub(1))
+        .map(char::len_utf16)
+        .sum();
+    Position::new(line as u32, character as u32)
+}
+
+/// Converts a position in the editor to a position in a diagram
+fn from_lsp(text: &str, position: Position) -> diagram_base::Position {
+    let mut units = 0;
+    let mut column = 1;
+    for c in text
+        .lines()
+        .nth(position.line as usize)
+        .unwrap_or("")
+        .chars()
+    {
+        if units >= position.character as usize {
+            break;
+        }
+        units += c.len_utf16();
+        column += 1;
+    }
+    diagram_base::Position::new(position.line as usize + 1, column)
+}
+
+fn range(text: &str, span: diagram_base::Span) -> Range {
+    Range::new(to_lsp(text, span.start), to_lsp(text, span.end))
+}
+
+fn error(code: ErrorCode, message: String) -> ResponseError {
+    ResponseError {
+        code: code as i32,
+        message,
+        data: None,
+    }
+}
+
+/// Decodes the parameters of a request and encodes the result of its handler
+fn call<P, R>(
+    params: Value,
+    handler: impl FnOnce(P) -> Result<R, ResponseError>,
+) -> Result<Value, ResponseError>
+where
+    P: DeserializeOwned,
+    R: Serialize,
+{
+    let params = serde_json::from_value(params)
+        .map_err(|err| error(ErrorCode::InvalidParams, err.to_string()))?;
+    let result = handler(params)?;
+    Ok(serde_json::to_value(result).unwrap())
+}
+
+/// Decodes the parameters of a notification, the invalid ones are ignored
+fn decode<P: DeserializeOwned>(params: Value) -> Option<P> {
+    serde_json::from_value(params).ok()
+}
+
+struct Document {
+    text: String,
+    resolver: FileResolver,
+    /// The analysis of the last version of the text that could be parsed
+    analysis: Analysis,
+    /// Whether the analysis is of the current text, the positions in it are wrong otherwise
+    up_to_date: bool,
+}
+
+impl Document {
+    fn new(uri: &Url, text: String) -> Self {
+        // The includes are resolved relative to the file, or to the working directory if the
+        // document is not saved yet
+        let resolver = match uri.to_file_path() {
+            Ok(path) => FileResolver::new(path),
+            Err(_) => FileResolver::in_directory(""),
+        };
+        let mut document = Self {
+            text: String::new(),
+            resolver,
+            analysis: Analysis::default(),
+            up_to_date: false,
+        };
+        document.update(text);
+        document
+    }
+
+    fn update(&mut self, text: String) {
+        self.text = text;
+        match diagram_seq::analyze(&self.text, &self.resolver) {
+            Ok(analysis) => {
+                self.analysis = analysis;
+                self.up_to_date = true;
+            }
+            Err(_) => self.up_to_date = false,
+        }
+    }
+
+    /// The problems of the document, both the errors and the warnings
+    fn diagnostics(&self) -> Vec<lsp_types::Diagnostic> {
+        let diagnostics = match diagram_seq::lint(&self.text, &self.resolver) {
+            Ok(diagnostics) => diagnostics,
+            Err(err) => vec![err.diagnostic().clone()],
+        };
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| {
+                let span = diagnostic.span.unwrap_or_else(|| {
+                    let start = diagram_base::Position::new(1, 1);
+                    diagram_base::Span::new(start, start)
+                });
+                let message = match diagnostic.help {
+                    Some(help) => format!("{}\nhelp: {}", diagnostic.message, help),
+                    None => diagnostic.message,
+                };
+                lsp_types::Diagnostic {
+                    range: range(&self.text, span),
+                    severity: Some(match diagnostic.severity {
+                        diagram_base::Severity::Error => DiagnosticSeverity::ERROR,
+                        diagram_base::Severity::Warning => DiagnosticSeverity::WARNING,
+                    }),
+                    code: Some(NumberOrString::String(diagnostic.code)),
+                    source: Some("diagwiz".to_string()),
+                    message,
+                    ..lsp_types::Diagnostic::default()
+                }
+            })
+            .collect()
+    }
+
+    /// Finds the participant ID at the position, unless the text cannot be analysed
+    fn occurrence_at(&self, position: Position) -> Option<&Occurrence> {
+        if !self.up_to_date {
+            return None;
+        }
+        let position = from_lsp(&self.text, position);
+        self.analysis.occurrence_at(position.line, position.column)
+    }
+}
+
+/// The state of the language server, i.e. the documents open in the editor
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn document(&self, uri: &Url) -> Result<&Document, ResponseError> {
+        self.documents.get(uri).ok_or_else(|| {
+            error(
+                ErrorCode::InvalidParams,
+                format!("unknown document {}", uri),
+            )
+        })
+    }
+
+    fn publish_diagnostics(&self, uri: Url) -> Notification {
+        let diagnostics = match self.documents.get(&uri) {
+            Some(document) => document.diagnostics(),
+            None => Vec::new(),
+        };
+        Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            PublishDiagnosticsParams::new(uri, diagnostics, None),
+        )
+    }
+
+    /// Handles a notification, returning the notifications to send back
+    pub fn handle_notification(&mut self, notification: Notification) -> Vec<Notification> {
+        let Notification { method, params } = notification;
+        let uri = match method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams = match decode(params) {
+                    Some(params) => params,
+                    None => return Vec::new(),
+                };
+                let uri = params.text_document.uri;
+                let document = Document::new(&uri, params.text_document.text);
+                self.documents.insert(uri.clone(), document);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams = match decode(params) {
+                    Some(params) => params,
+                    None => return Vec::new(),
+                };
+                let uri = params.text_document.uri;
+                // The whole text is sent on every change
+                let change = params.content_changes.into_iter().last();
+                if let (Some(document), Some(change)) = (self.documents.get_mut(&uri), change) {
+                    document.update(change.text);
+                }
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: lsp_types::DidCloseTextDocumentParams = match decode(params) {
+                    Some(params) => params,
+                    None => return Vec::new(),
+                };
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                uri
+            }
+            _ => return Vec::new(),
+        };
+        vec![self.publish_diagnostics(uri)]
+    }
+
+    pub fn handle_request(&mut self, request: Request) -> Response {
+        let Request { id, method, params } = request;
+        let result = match method.as_str() {
+            Completion::METHOD => call(params, |params| self.completion(params)),
+            GotoDefinition::METHOD => call(params, |params| self.definition(params)),
+            HoverRequest::METHOD => call(params, |params| self.hover(params)),
+            Rename::METHOD => call(params, |params| self.rename(params)),
+            ExecuteCommand::METHOD => call(params, |params| self.execute_command(params)),
+            _ => Err(error(
+                ErrorCode::MethodNotFound,
+                format!("unsupported method {}", method),
+            )),
+        };
+        match result {
+            Ok(result) => Response::new_ok(id, result),
+            Err(error) => Response {
+                id,
+                result: None,
+                error: Some(error),
+            },
+        }
+    }
+
+    fn completion(&self, params: CompletionParams) -> Result<CompletionResponse, ResponseError> {
+        let position = params.text_document_position;
+        let document = self.document(&position.text_document.uri)?;
+
+        // The keywords start the statements, so they are only suggested for the first word
+        let column = from_lsp(&document.text, position.position).column;
+        let line = document.text.lines().nth(position.position.line as usize);
+        let prefix: String = line.unwrap_or("").chars().take(column - 1).collect();
+        let first_word = prefix
+            .trim_start()
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_');
+
+        let keywords = KEYWORDS
+            .iter()
+            .filter(|_| first_word)
+            .map(|keyword| CompletionItem {
+                label: keyword.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                ..CompletionItem::default()
+            });
+        let participants =
+            document
+                .analysis
+                .participants
+                .iter()
+                .map(|participant| CompletionItem {
+                    label: participant.id.clone(),
+                    kind: Some(CompletionItemKind::VARIABLE),
+                    detail: Some(participant.label.clone()),
+                    ..CompletionItem::default()
+                });
+        Ok(CompletionResponse::Array(
+            participants.chain(keywords).collect(),
+        ))
+    }
+
+    fn definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>, ResponseError> {
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        let document = self.document(&uri)?;
+        let declaration = document
+            .occurrence_at(position.position)
+            .and_then(|occurrence| document.analysis.declaration(&occurrence.id));
+        Ok(declaration.map(|declaration| {
+            GotoDefinitionResponse::Scalar(Location::new(
+                uri.clone(),
+                range(&document.text, declaration.span),
+            ))
+        }))
+    }
+
+    fn hover(&self, params: HoverParams) -> Result<Option<Hover>, ResponseError> {
+        let position = params.text_document_position_params;
+        let document = self.document(&position.text_document.uri)?;
+        let occurrence = match document.occurrence_at(position.position) {
+            Some(occurrence) => occurrence,
+            None => return Ok(None),
+        };
+        Ok(document
+            .analysis
+            .participant(&occurrence.id)
+            .map(|participant| Hover {
+                contents: HoverContents::Markup(MarkupContent {
+                    kind: MarkupKind::Markdown,
+                    value: format!("**{}** (`{}`)", participant.label, participant.id),
+                }),
+                range: Some(range(&document.text, occurrence.span)),
+            }))
+    }
+
+    fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>, ResponseError> {
+        let RenameParams {
+            text_document_position: position,
+            new_name,
+            ..
+        } = params;
+        let uri = position.text_document.uri;
+        let document = self.document(&uri)?;
+        if !diagram_seq::is_identifier(&new_name) {
+            return Err(error(
+                ErrorCode::InvalidParams,
+                format!("`{}` is not a valid participant ID", new_name),
+            ));
+        }
+        let id = match document.occurrence_at(position.position) {
+            Some(occurrence) => &occurrence.id,
+            None => return Ok(None),
+        };
+
+        let edits = document
+            .analysis
+            .occurrences
+            .iter()
+            .filter(|occurrence| &occurrence.id == id)
+            .map(|occurrence| {
+                TextEdit::new(range(&document.text, occurrence.span), new_name.clone())
+            })
+            .collect();
+        let mut changes = HashMap::new();
+        changes.insert(uri, edits);
+        Ok(Some(WorkspaceEdit::new(changes)))
+    }
+
+    fn execute_command(&self, params: ExecuteCommandParams) -> Result<Value, ResponseError> {
+        if params.command != PREVIEW_COMMAND {
+            return Err(error(
+                ErrorCode::InvalidParams,
+                format!("unknown command {}", params.command),
+            ));
+        }
+        let mut arguments = params.arguments.into_iter();
+        let uri = arguments
+            .next()
+            .and_then(|uri| serde_json::from_value::<Url>(uri).ok())
+            .ok_or_else(|| error(ErrorCode::InvalidParams, "missing document URI".to_string()))?;
+        let format = match arguments.next() {
+            Some(Value::String(format)) => OutputFormat::from_str(&format)
+                .map_err(|err| error(ErrorCode::InvalidParams, err))?,
+            _ => OutputFormat::Ascii,
+        };
+
+        let document = self.document(&uri)?;
+        let options = TransformOptions {
+            format,
+            ..TransformOptions::default()
+        };
+        match diagram_seq::transform_with_resolver(&document.text, &options, &document.resolver) {
+            Ok(output) => Ok(Value::String(output)),
+            Err(err) => Err(error(
+                ErrorCode::RequestFailed,
+                err.diagnostic().message.clone(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lsp_server::RequestId;
+    use serde_json::json;
+
+    const URI: &str = "file:///tmp/test.diag";
+
+    fn open(server: &mut Server, text: &str) -> Vec<lsp_types::Diagnostic> {
+        let notification = Notification::new(
+            DidOpenTextDocument::METHOD.to_string(),
+            json!({
+                "textDocument": {"uri": URI, "languageId": "diag", "version": 1, "text": text}
+            }),
+        );
+        published(server.handle_notification(notification))
+    }
+
+    fn published(notifications: Vec<Notification>) -> Vec<lsp_types::Diagnostic> {
+        assert_eq!(notifications.len(), 1);
+        let notification = notifications.into_iter().next().unwrap();
+        assert_eq!(notification.method, PublishDiagnostics::METHOD);
+        serde_json::from_value::<PublishDiagnosticsParams>(notification.params)
+            .unwrap()
+            .diagnostics
+    }
+
+    fn request(server: &mut Server, method: &str, params: Value) -> Result<Value, ResponseError> {
+        let response =
+            server.handle_request(Request::new(RequestId::from(1), method.to_string(), params));
+        match response.error {
+            Some(error) => Err(error),
+            None => Ok(response.result.unwrap()),
+        }
+    }
+
+    fn at(line: u32, character: u32) -> Value {
+        json!({"textDocument": {"uri": URI}, "position": {"line": line, "character": character}})
+    }
+
+    #[test]
+    fn convert_positions() {
+        let text = "a->b: \"😀\"\nb->c\n";
+        let position = diagram_base::Position::new(1, 9);
+        assert_eq!(to_lsp(text, position), Position::new(0, 9));
+        assert_eq!(from_lsp(text, Position::new(0, 9)), position);
+        assert_eq!(
+            from_lsp(text, Position::new(1, 3)),
+            diagram_base::Position::new(2, 4)
+        );
+    }
+
+    #[test]
+    fn publish_diagnostics_on_change() {
+        let mut server = Server::default();
+        let diagnostics = open(&mut server, "alias a=\"Alice\"\nb->c\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(
+            diagnostics[0].range,
+            Range::new(Position::new(0, 6), Position::new(0, 7))
+        );
+
+        let notification = Notification::new(
+            DidChangeTextDocument::METHOD.to_string(),
+            json!({
+                "textDocument": {"uri": URI, "version": 2},
+                "contentChanges": [{"text": "a->\n"}]
+            }),
+        );
+        let diagnostics = published(server.handle_notification(notification));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(
+            diagnostics[0].code,
+            Some(NumberOrString::String("E0001".to_string()))
+        );
+    }
+
+    #[test]
+    fn complete_participants_and_keywords() {
+        let mut server = Server::default();
+        open(&mut server, "alias a=\"Alice\"\na->b\n");
+        let labels = |result: Value| -> Vec<String> {
+            match serde_json::from_value(result).unwrap() {
+                CompletionResponse::Array(items) => {
+                    items.into_iter().map(|item| item.label).collect()
+                }
+                _ => panic!("expected a list of completions"),
+            }
+        };
+
+        let completions = labels(request(&mut server, Completion::METHOD, at(1, 0)).unwrap());
+        assert_eq!(&completions[..2], &["a", "b"]);
+        assert!(completions.contains(&"alias".to_string()));
+        let completions = labels(request(&mut server, Completion::METHOD, at(1, 3)).unwrap());
+        assert_eq!(completions, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn go_to_definition_and_hover() {
+        let mut server = Server::default();
+        open(&mut server, "alias alice=\"Alice\"\nalice->bob\n");
+
+        let definition = request(&mut server, GotoDefinition::METHOD, at(1, 2)).unwrap();
+        let definition: GotoDefinitionResponse = serde_json::from_value(definition).unwrap();
+        assert_eq!(
+            definition,
+            GotoDefinitionResponse::Scalar(Location::new(
+                Url::parse(URI).unwrap(),
+                Range::new(Position::new(0, 6), Position::new(0, 11))
+            ))
+        );
+        let definition = request(&mut server, GotoDefinition::METHOD, at(1, 8)).unwrap();
+        assert_eq!(definition, Value::Null);
+
+        let hover = request(&mut server, HoverRequest::METHOD, at(1, 2)).unwrap();
+        let hover: Hover = serde_json::from_value(hover).unwrap();
+        assert_eq!(
+            hover.contents,
+            HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: "**Alice** (`alice`)".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rename_participants() {
+        let mut server = Server::default();
+        open(
+            &mut server,
+            "alias a=\"Alice\"\na->b\nnote over a: \"hi\"\n",
+        );
+        let mut params = at(1, 0);
+        params["newName"] = json!("alice");
+        let edit = request(&mut server, Rename::METHOD, params.clone()).unwrap();
+        let edit: WorkspaceEdit = serde_json::from_value(edit).unwrap();
+        let edits = &edit.changes.unwrap()[&Url::parse(URI).unwrap()];
+        assert_eq!(
+            edits
+                .iter()
+                .map(|edit| edit.range.start)
+                .collect::<Vec<_>>(),
+            vec![
+                Position::new(0, 6),
+                Position::new(1, 0),
+                Position::new(2, 10)
+            ]
+        );
+
+        params["newName"] = json!("not valid");
+        assert!(request(&mut server, Rename::METHOD, params).is_err());
+    }
+
+    #[test]
+    fn preview_diagrams() {
+        let mut server = Server::default();
+        open(&mut server, "a->b\n");
+        let preview = request(
+            &mut server,
+            ExecuteCommand::METHOD,
+            json!({"command": PREVIEW_COMMAND, "arguments": [URI, "svg"]}),
+        )
+        .unwrap();
+        assert!(preview.as_str().unwrap().starts_with("<svg"));
+        let params = json!({"command": PREVIEW_COMMAND, "arguments": []});
+        assert!(request(&mut server, ExecuteCommand::METHOD, params).is_err());
+    }
+}